@@ -0,0 +1,61 @@
+use crate::*;
+
+/*
+ * Checkout of several raffles at once: every raffle of the cart is checked before anything is
+ * bought, so either all tickets are sold or the call fails and the deposit goes back.
+ * One attached deposit pays for everything, ticket storage included, what's left over is
+ * returned in one transfer.
+ * An account still holds one ticket it bought per raffle, so `count` can only be 1 for now.
+ * USD raffles are bought with `buy_usd_ticket`, their price isn't known until the oracle answers.
+ */
+
+pub const MAX_BATCH_RAFFLES: usize = 10;
+
+#[near_bindgen]
+impl RafflesMap {
+    // Buys `count` tickets of each listed raffle for the caller, returns what was spent
+    #[payable]
+    pub fn buy_tickets_batch(&mut self, tickets: Vec<(U128, U64)>, referrer: Option<AccountId>) -> U128 {
+        assert!(
+            !tickets.is_empty() && tickets.len() <= MAX_BATCH_RAFFLES,
+            "A batch needs 1 to {} raffles",
+            MAX_BATCH_RAFFLES
+        );
+        let buyer = env::predecessor_account_id();
+        let now = block_timestamp_ms();
+
+        let mut prices: Vec<(u128, u128)> = vec![];
+        for (index, (key, count)) in tickets.iter().enumerate() {
+            let key = key.0;
+            assert_eq!(count.0, 1, "Only one ticket per raffle and account");
+            assert!(
+                !tickets[..index].iter().any(|(other, _)| other.0 == key),
+                "Raffle {} is listed twice",
+                key
+            );
+            let raffle = self.raffles.get(&key).expect("No such raffle");
+            assert!(raffle.game_continues && raffle.end_time >= now, "Raffle {} is over", key);
+            assert!(
+                raffle.max_tickets.is_none_or(|max| raffle.participants.len() < max),
+                "Raffle {} is sold out",
+                key
+            );
+            assert!(raffle.pricing.usd().is_none(), "Raffle {} is priced in USD, use buy_usd_ticket", key);
+            assert!(!raffle.participants.contains(&buyer), "{} already has a ticket of raffle {}", buyer, key);
+            prices.push((key, raffle.current_price()));
+        }
+
+        let storage_cost = ticket_storage_cost();
+        let total: u128 = prices.iter().map(|(_, price)| price + storage_cost).sum();
+        let deposit = env::attached_deposit();
+        assert!(deposit >= total, "The batch costs {}, attached {}", total, deposit);
+
+        for (key, price) in prices {
+            assert!(self.internal_sell_ticket(key, &buyer, &buyer, referrer.clone(), price + storage_cost, price));
+        }
+        if deposit > total {
+            Promise::new(buyer).transfer(deposit - total);
+        }
+        U128(total)
+    }
+}
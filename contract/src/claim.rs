@@ -0,0 +1,115 @@
+use crate::*;
+
+/*
+ * Claim mode: instead of pushing prizes to winners, which fails for deleted accounts or NFT
+ * contracts asking for storage, winners call `claim_prize` before their deadline.
+ * After the deadline anyone can call `expire_prize`: the prize is redrawn among the
+ * ticket holders without a prize, or goes back to its depositor once the redraws are used up.
+ * Prizes of push mode raffles that couldn't be sent wait for a claim the same way, for
+ * `UNSENT_CLAIM_HOURS`. Revenue of a prize is settled when it's delivered or returned.
+ */
+
+pub const MAX_REDRAWS: u64 = 3;
+// claim window of a push mode prize whose transfer failed
+pub const UNSENT_CLAIM_HOURS: u64 = 7 * 24;
+// random picks tried to find a participant without a prize, participants aren't scanned
+pub const REDRAW_ATTEMPTS: u64 = 20;
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum PrizeStatus {
+    // pushed to the winner, transfer not resolved yet
+    Sending,
+    Delivered,
+    // waiting for the winner to claim it
+    Claimable,
+    // claimed, transfer not resolved yet
+    Claiming,
+    // nobody claimed it, given back to its depositor
+    Returned,
+}
+
+#[near_bindgen]
+impl RafflesMap {
+    // Sends the caller every prize of the raffle they can still claim, returns how many
+    pub fn claim_prize(&mut self, key: U128) -> U64 {
+        let key = key.0;
+        let winner_id = env::predecessor_account_id();
+        let mut raffle = self.raffles.get(&key).expect("No such raffle");
+        let now = block_timestamp_ms();
+
+        let mut claimed: u64 = 0;
+        for index in 0..raffle.winners.len() {
+            let mut winner = raffle.winners.get(index).unwrap();
+            if winner.winner_wallet_account_id != winner_id
+                || winner.status != PrizeStatus::Claimable
+                || winner.claim_deadline.is_some_and(|deadline| deadline.0 < now)
+            {
+                continue;
+            }
+            winner.status = PrizeStatus::Claiming;
+            raffle.winners.replace(index, &winner);
+            winner.send_prize_to_winner().then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas(30 * TGAS))
+                    .resolve_prize_transfer(key, index),
+            );
+            claimed += 1;
+        }
+        assert!(claimed > 0, "Nothing to claim");
+        U64(claimed)
+    }
+
+    // Redraws an unclaimed prize after its deadline, or returns it to its depositor
+    pub fn expire_prize(&mut self, key: U128, index: U64) -> PrizeStatus {
+        let (key, index) = (key.0, index.0);
+        let mut raffle = self.raffles.get(&key).expect("No such raffle");
+        let mut winner = raffle.winners.get(index).expect("No such winner");
+        assert_eq!(winner.status, PrizeStatus::Claimable, "The prize isn't waiting for a claim");
+        assert!(
+            winner.claim_deadline.is_some_and(|deadline| deadline.0 < block_timestamp_ms()),
+            "The prize can still be claimed"
+        );
+
+        if winner.redraws.0 < MAX_REDRAWS {
+            let mut rng = Rng::new(&env::random_seed());
+            if let Some((ticket, next)) = self.find_redraw_candidate(key, &raffle, &mut rng) {
+                log!("Prize {} of raffle {} is redrawn to {}", winner.prize.token_id, key, next);
+                winner.winner_wallet_account_id = next;
+                winner.ticket = U64(ticket);
+                winner.redraws.0 += 1;
+                winner.claim_deadline = Some(U64(raffle.claim_deadline()));
+                raffle.winners.replace(index, &winner);
+                return winner.status;
+            }
+        }
+
+        log!("Prize {} of raffle {} goes back to its depositor", winner.prize.token_id, key);
+        winner.status = PrizeStatus::Returned;
+        raffle.winners.replace(index, &winner);
+        self.return_prize_to_depositor(&winner.prize);
+        self.settle_prize(key, raffle, index);
+        winner.status
+    }
+}
+
+impl Raffle {
+    pub(crate) fn claim_deadline(&self) -> u64 {
+        block_timestamp_ms() + self.claim_hours.unwrap_or(UNSENT_CLAIM_HOURS) * MS_IN_HOUR
+    }
+}
+
+impl RafflesMap {
+    // a ticket whose holder has no prize of the raffle, the expired winner included
+    fn find_redraw_candidate(&self, key: u128, raffle: &Raffle, rng: &mut Rng) -> Option<(u64, AccountId)> {
+        let prize_holders: Vec<AccountId> = raffle
+            .winners
+            .iter()
+            .map(|winner| winner.winner_wallet_account_id)
+            .collect();
+        (0..REDRAW_ATTEMPTS)
+            .filter_map(|_| raffle.get_random_ticket(rng))
+            .map(|ticket| (ticket, self.ticket_holder(key, raffle, ticket)))
+            .find(|(_, holder)| !prize_holders.contains(holder))
+    }
+}
@@ -0,0 +1,133 @@
+use crate::*;
+
+/*
+ * Anti-spam settings managed by the contract account: a creation fee taken from the creator's
+ * ledger balance, a limit of open raffles per creator and a blocklist of creators.
+ * The creation fee only changes through a timelocked config change.
+ * Series rounds are opened by the contract itself and don't count against the limit.
+ */
+
+pub const DEFAULT_MAX_OPEN_RAFFLES: u64 = 10;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Config {
+    pub(crate) creation_fee: u128,
+    pub(crate) fee_token: Asset,
+    max_open_raffles: u64,
+    // see `governance.rs`
+    pub(crate) platform_fee_bps: u16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            creation_fee: 0,
+            fee_token: Asset::Near,
+            max_open_raffles: DEFAULT_MAX_OPEN_RAFFLES,
+            platform_fee_bps: 0,
+        }
+    }
+}
+
+// creation fee taken for a raffle, given back if the raffle isn't created
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Fee {
+    pub token: Asset,
+    pub amount: U128,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ConfigView {
+    pub beneficiary: AccountId,
+    pub creation_fee: Fee,
+    pub max_open_raffles: U64,
+    pub referral_bps: u16,
+    pub platform_fee_bps: u16,
+    pub blocked_creators: U64,
+}
+
+#[near_bindgen]
+impl RafflesMap {
+    #[private]
+    pub fn set_max_open_raffles(&mut self, max_open_raffles: U64) {
+        self.config.max_open_raffles = max_open_raffles.0;
+    }
+
+    #[private]
+    pub fn block_creator(&mut self, account_id: AccountId) {
+        self.blocklist.insert(&account_id);
+    }
+
+    #[private]
+    pub fn unblock_creator(&mut self, account_id: AccountId) {
+        self.blocklist.remove(&account_id);
+    }
+
+    pub fn is_blocked(&self, account_id: AccountId) -> bool {
+        self.blocklist.contains(&account_id)
+    }
+
+    pub fn get_open_raffles(&self, account_id: AccountId) -> U64 {
+        U64(self.open_raffles.get(&account_id).unwrap_or(0))
+    }
+
+    pub fn get_config(&self) -> ConfigView {
+        ConfigView {
+            beneficiary: self.beneficiary.clone(),
+            creation_fee: self.creation_fee(),
+            max_open_raffles: U64(self.config.max_open_raffles),
+            referral_bps: self.referral_bps,
+            platform_fee_bps: self.config.platform_fee_bps,
+            blocked_creators: U64(self.blocklist.len()),
+        }
+    }
+}
+
+impl RafflesMap {
+    pub(crate) fn creation_fee(&self) -> Fee {
+        Fee {
+            token: self.config.fee_token.clone(),
+            amount: U128(self.config.creation_fee),
+        }
+    }
+
+    // Checks the creator may open one more raffle, takes the fee from their ledger balance and
+    // holds the slot; `release_creation` undoes it if the raffle isn't created after all
+    pub(crate) fn reserve_creation(&mut self, creator: &AccountId) -> Fee {
+        assert!(!self.blocklist.contains(creator), "{} can't create raffles", creator);
+
+        let open = self.get_open_raffles(creator.clone()).0;
+        assert!(
+            open < self.config.max_open_raffles,
+            "{} already has {} open raffles",
+            creator,
+            open
+        );
+        self.open_raffles.insert(creator, &(open + 1));
+
+        let fee = self.creation_fee();
+        self.debit(creator, fee.token.clone(), fee.amount.0);
+        fee
+    }
+
+    pub(crate) fn release_creation(&mut self, creator: &AccountId, fee: Fee) {
+        self.release_open_slot(creator);
+        self.credit(creator, fee.token, fee.amount.0);
+    }
+
+    pub(crate) fn collect_creation_fee(&mut self, fee: Fee) {
+        let beneficiary = self.beneficiary.clone();
+        self.credit(&beneficiary, fee.token, fee.amount.0);
+    }
+
+    pub(crate) fn release_open_slot(&mut self, creator: &AccountId) {
+        let open = self.get_open_raffles(creator.clone()).0;
+        if open > 1 {
+            self.open_raffles.insert(creator, &(open - 1));
+        } else {
+            self.open_raffles.remove(creator);
+        }
+    }
+}
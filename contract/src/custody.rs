@@ -0,0 +1,103 @@
+use crate::*;
+
+/*
+ * Custody of the NFTs the contract holds. Prizes come in with `nft_transfer_call`, which tells us
+ * who deposited them: an empty msg keeps the NFT for a raffle of the depositor, a series msg adds
+ * it to that series' treasury. A held NFT is the prize of one raffle or series at a time, only its
+ * depositor can list it, and it only ever goes back to the depositor. The record is dropped when
+ * the NFT leaves the contract. NFTs sent with a plain `nft_transfer` can't be prizes.
+ */
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde", tag = "kind", rename_all = "snake_case")]
+pub enum PrizeCommitment {
+    Raffle { raffle_id: U128 },
+    // in the treasury or in a round of the series
+    Series { series_id: U64 },
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HeldPrize {
+    pub depositor: AccountId,
+    // None until the depositor lists it
+    pub commitment: Option<PrizeCommitment>,
+}
+
+#[near_bindgen]
+impl RafflesMap {
+    pub fn get_held_prize(&self, nft_contract_id: AccountId, token_id: TokenId) -> Option<HeldPrize> {
+        self.held_prizes.get(&(nft_contract_id, token_id))
+    }
+
+    // Sends a deposited NFT that isn't listed anywhere back to its depositor
+    pub fn withdraw_prize(&mut self, nft_contract_id: AccountId, token_id: TokenId) -> Promise {
+        let key = (nft_contract_id, token_id);
+        let held = self.held_prizes.get(&key).expect("No such deposit");
+        assert_eq!(held.depositor, env::predecessor_account_id(), "Only the depositor can withdraw it");
+        assert!(held.commitment.is_none(), "Token {} is a prize", key.1);
+
+        self.held_prizes.remove(&key);
+        nft_contract::ext(key.0)
+            .with_static_gas(Gas(10 * TGAS))
+            .with_attached_deposit(1)
+            .nft_transfer(held.depositor, key.1, None, None)
+    }
+}
+
+impl RafflesMap {
+    pub(crate) fn record_deposit(&mut self, prize: &JsonToken, depositor: AccountId, commitment: Option<PrizeCommitment>) {
+        self.held_prizes.insert(&prize_key(prize), &HeldPrize { depositor, commitment });
+    }
+
+    // Lists the creator's deposits as prizes of `commitment`; approval prizes aren't held
+    pub(crate) fn commit_prizes(&mut self, prizes: &[JsonToken], creator: &AccountId, commitment: PrizeCommitment) {
+        for prize in prizes.iter().filter(|prize| prize.approval_id.is_none()) {
+            let key = prize_key(prize);
+            let mut held = self.held_prizes.get(&key).unwrap_or_else(|| {
+                env::panic_str(&format!("Prize {} of {} wasn't deposited with nft_transfer_call", key.1, key.0))
+            });
+            assert_eq!(&held.depositor, creator, "Prize {} of {} was deposited by someone else", key.1, key.0);
+            assert!(held.commitment.is_none(), "Prize {} of {} is already listed", key.1, key.0);
+            held.commitment = Some(commitment.clone());
+            self.held_prizes.insert(&key, &held);
+        }
+    }
+
+    // the raffle `key` wasn't created, its prizes can be listed again
+    pub(crate) fn uncommit_prizes(&mut self, prizes: &[JsonToken], key: u128) {
+        for prize in prizes {
+            if let Some(mut held) = self.held_prizes.get(&prize_key(prize)) {
+                if held.commitment == Some(PrizeCommitment::Raffle { raffle_id: U128(key) }) {
+                    held.commitment = None;
+                    self.held_prizes.insert(&prize_key(prize), &held);
+                }
+            }
+        }
+    }
+
+    // the prize left the contract
+    pub(crate) fn release_prize(&mut self, prize: &JsonToken) {
+        self.held_prizes.remove(&prize_key(prize));
+    }
+
+    // sends a prize we hold back to whoever deposited it; approval prizes never left their owner
+    pub(crate) fn return_prize_to_depositor(&mut self, prize: &JsonToken) {
+        if prize.approval_id.is_some() {
+            return;
+        }
+        let Some(held) = self.held_prizes.get(&prize_key(prize)) else {
+            log!("Prize {} of {} has no depositor, it stays here", prize.token_id, prize.owner_id);
+            return;
+        };
+        self.release_prize(prize);
+        nft_contract::ext(prize.owner_id.clone())
+            .with_static_gas(Gas(10 * TGAS))
+            .with_attached_deposit(1)
+            .nft_transfer(held.depositor, prize.token_id.clone(), None, None);
+    }
+}
+
+fn prize_key(prize: &JsonToken) -> (AccountId, TokenId) {
+    (prize.owner_id.clone(), prize.token_id.clone())
+}
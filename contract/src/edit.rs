@@ -0,0 +1,175 @@
+use crate::*;
+
+/*
+ * Creators can fix a raffle until the first ticket is sold. Approval raffles keep their single
+ * prize, and series rounds can't be edited at all since they follow their series.
+ */
+
+#[near_bindgen]
+impl RafflesMap {
+    // `end_time` is in hours from now, like in add_new_raffle; `max_tickets` 0 removes the limit
+    pub fn edit_raffle(
+        &mut self,
+        key: U128,
+        ticket_price: Option<U128>,
+        end_time: Option<U64>,
+        max_tickets: Option<U64>,
+        metadata: Option<RaffleMetadata>,
+        pricing: Option<Pricing>,
+        reserve: Option<Reserve>,
+    ) {
+        let key = key.0;
+        let mut raffle = self.raffles.get(&key).expect("No such raffle");
+        assert_editable(&raffle);
+        assert_valid_hours(&[end_time.map(|end_time| end_time.0), reserve.as_ref().and_then(Reserve::extension_hours)]);
+
+        let mut edits: Vec<RaffleEditLog> = Vec::new();
+        let mut edit = |field: &str, value: String| {
+            edits.push(RaffleEditLog {
+                raffle_id: U128(key),
+                field: field.to_string(),
+                value,
+            })
+        };
+
+        if let Some(U128(ticket_price)) = ticket_price {
+            raffle.ticket_price = ticket_price;
+            edit("ticket_price", ticket_price.to_string());
+        }
+        if let Some(U64(end_time)) = end_time {
+            raffle.end_time = end_time_from_hours(end_time);
+            edit("end_time", raffle.end_time.to_string());
+        }
+        if let Some(U64(max_tickets)) = max_tickets {
+            raffle.max_tickets = Some(max_tickets).filter(|max_tickets| *max_tickets > 0);
+            edit("max_tickets", max_tickets.to_string());
+        }
+        if let Some(pricing) = pricing {
+            edit("pricing", near_sdk::serde_json::to_string(&pricing).unwrap());
+            raffle.pricing = pricing;
+        }
+        if let Some(reserve) = reserve {
+            edit("reserve", near_sdk::serde_json::to_string(&reserve).unwrap());
+            raffle.reserve = Some(reserve).filter(|reserve| reserve.revenue.0 > 0);
+        }
+        if let Some(metadata) = metadata {
+            metadata.assert_valid();
+            edit("metadata", near_sdk::serde_json::to_string(&metadata).unwrap());
+            self.unindex_categories(key, &raffle.metadata);
+            self.index_categories(key, &metadata);
+            raffle.metadata = metadata;
+        }
+
+        raffle.pricing.assert_valid(raffle.ticket_price);
+        self.raffles.insert(&key, &raffle);
+        if !edits.is_empty() {
+            emit_event(EventLogVariant::RaffleEdit(edits));
+        }
+    }
+
+    // The prize has to be sent to the contract with nft_transfer_call first, same as for add_new_raffle
+    pub fn add_raffle_prize(&mut self, key: U128, prize: JsonToken) -> Promise {
+        let key = key.0;
+        let raffle = self.raffles.get(&key).expect("No such raffle");
+        assert_editable(&raffle);
+        assert_own_prizes(&raffle);
+        assert!(raffle.prizes.len() < MAX_PRIZES_PER_RAFFLE, "The raffle already has {} prizes", MAX_PRIZES_PER_RAFFLE);
+
+        let prize = JsonToken { approval_id: None, ..prize };
+        self.commit_prizes(std::slice::from_ref(&prize), raffle.get_creator(), PrizeCommitment::Raffle { raffle_id: U128(key) });
+        nft_contract::ext(prize.owner_id.clone())
+            .with_static_gas(Gas(5 * TGAS))
+            .nft_token(prize.token_id.clone())
+            .and(nft_contract::ext(prize.owner_id.clone()).with_static_gas(Gas(5 * TGAS)).nft_metadata())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas(10 * TGAS))
+                    .check_prize_and_add(key, prize),
+            )
+    }
+
+    #[private]
+    pub fn check_prize_and_add(
+        &mut self,
+        #[callback_result] call_result: Result<Token, PromiseError>,
+        #[callback_result] collection: Result<NFTContractMetadata, PromiseError>,
+        key: u128,
+        prize: JsonToken,
+    ) -> bool {
+        let token = call_result
+            .ok()
+            .filter(|token| token.token_id == prize.token_id && token.owner_id == env::current_account_id());
+        // the raffle could have been sold, drawn or filled up while we were waiting for the NFT contract
+        let raffle = self.raffles.get(&key).filter(|raffle| {
+            raffle.game_continues
+                && !raffle.draw_started
+                && raffle.end_time >= block_timestamp_ms()
+                && raffle.participants.is_empty()
+                && raffle.prizes.len() < MAX_PRIZES_PER_RAFFLE
+                && !raffle.prizes.contains(&prize)
+        });
+        let (mut raffle, token) = match (raffle, token) {
+            (Some(raffle), Some(token)) => (raffle, token),
+            _ => {
+                log!("Prize {} can't be added to raffle {}", prize.token_id, key);
+                self.uncommit_prizes(&[prize], key);
+                return false;
+            }
+        };
+
+        raffle.prizes.push(prize.clone());
+        raffle.prize_metadata.push(Some(PrizeMetadata::new(&token, collection.ok().as_ref())));
+        self.raffles.insert(&key, &raffle);
+        emit_event(EventLogVariant::RafflePrizeAdded(vec![RafflePrizeLog {
+            raffle_id: U128(key),
+            prize,
+        }]));
+        true
+    }
+
+    // Sends the prize back to its depositor, a raffle keeps at least one prize
+    pub fn remove_raffle_prize(&mut self, key: U128, prize: JsonToken) {
+        let key = key.0;
+        let mut raffle = self.raffles.get(&key).expect("No such raffle");
+        assert_editable(&raffle);
+        assert_own_prizes(&raffle);
+
+        let index = raffle
+            .prizes
+            .iter()
+            .position(|p| p.token_id == prize.token_id && p.owner_id == prize.owner_id)
+            .expect("No such prize in the raffle");
+        assert!(raffle.prizes.len() > 1, "The last prize can't be removed");
+        let removed = raffle.prizes.remove(index);
+        raffle.prize_metadata.remove(index);
+        self.raffles.insert(&key, &raffle);
+
+        emit_event(EventLogVariant::RafflePrizeRemoved(vec![RafflePrizeLog {
+            raffle_id: U128(key),
+            prize: removed.clone(),
+        }]));
+        self.return_prize_to_depositor(&removed);
+    }
+}
+
+fn assert_editable(raffle: &Raffle) {
+    assert_eq!(
+        raffle.get_creator(),
+        &env::predecessor_account_id(),
+        "Only the creator can edit the raffle"
+    );
+    assert!(raffle.series_id.is_none(), "Series rounds can't be edited");
+    assert!(raffle.no_loss.is_none(), "No-loss raffles can't be edited");
+    assert!(
+        raffle.game_continues && !raffle.draw_started && raffle.end_time >= block_timestamp_ms(),
+        "The raffle is over"
+    );
+    assert!(raffle.participants.is_empty(), "Tickets were already sold");
+}
+
+fn assert_own_prizes(raffle: &Raffle) {
+    assert!(
+        raffle.prizes.iter().all(|prize| prize.approval_id.is_none()),
+        "Prizes of approval raffles can't be changed"
+    );
+}
@@ -0,0 +1,95 @@
+use std::fmt;
+
+use crate::*;
+
+/*
+ * NEP-297 events, logged as `EVENT_JSON:{"standard":"raffland","version":"1.0.0","event":...}`
+ * so indexers can follow raffles without polling the views.
+ */
+
+pub const EVENT_STANDARD: &str = "raffland";
+pub const EVENT_VERSION: &str = "1.0.0";
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde", tag = "event", content = "data", rename_all = "snake_case")]
+pub enum EventLogVariant {
+    RaffleCreated(Vec<RaffleCreatedLog>),
+    RaffleEdit(Vec<RaffleEditLog>),
+    RafflePrizeAdded(Vec<RafflePrizeLog>),
+    RafflePrizeRemoved(Vec<RafflePrizeLog>),
+    RaffleReserveNotMet(Vec<RaffleReserveLog>),
+    JackpotWon(Vec<JackpotWin>),
+    TicketPurchased(Vec<TicketPurchaseLog>),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EventLog {
+    pub standard: String,
+    pub version: String,
+    #[serde(flatten)]
+    pub event: EventLogVariant,
+}
+
+impl fmt::Display for EventLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "EVENT_JSON:{}",
+            &near_sdk::serde_json::to_string(self).map_err(|_| fmt::Error)?
+        ))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RaffleCreatedLog {
+    pub raffle_id: U128,
+    pub creator: AccountId,
+    pub prizes: Vec<JsonToken>,
+    pub metadata: RaffleMetadata,
+}
+
+// a field of a raffle changed by its creator; `value` is the new value as JSON
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RaffleEditLog {
+    pub raffle_id: U128,
+    pub field: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RafflePrizeLog {
+    pub raffle_id: U128,
+    pub prize: JsonToken,
+}
+
+// raffle ended below its reserve; without `extended_until` it's aborted
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RaffleReserveLog {
+    pub raffle_id: U128,
+    pub revenue: U128,
+    pub extended_until: Option<U64>,
+}
+
+// `payer` differs from `beneficiary` for gifted tickets
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TicketPurchaseLog {
+    pub raffle_id: U128,
+    pub ticket: U64,
+    pub payer: AccountId,
+    pub beneficiary: AccountId,
+    pub price: U128,
+}
+
+pub(crate) fn emit_event(event: EventLogVariant) {
+    let log = EventLog {
+        standard: EVENT_STANDARD.to_string(),
+        version: EVENT_VERSION.to_string(),
+        event,
+    };
+    env::log_str(&log.to_string());
+}
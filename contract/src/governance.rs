@@ -0,0 +1,115 @@
+use crate::*;
+
+/*
+ * Settings that touch users' money only change through a timelock: the contract account
+ * proposes a change, anyone can execute it `CONFIG_TIMELOCK_MS` later, and until then it's
+ * listed by `get_config_changes` and can be cancelled. The platform fee is copied into raffles
+ * on creation like the referral and jackpot shares, so running raffles keep the rate they
+ * started with. It's paid once the first prize is settled, aborted raffles pay none.
+ */
+
+pub const CONFIG_TIMELOCK_MS: u64 = 48 * MS_IN_HOUR;
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde", tag = "kind", rename_all = "snake_case")]
+pub enum ConfigChange {
+    Beneficiary { account_id: AccountId },
+    // share of the revenue of new raffles going to the beneficiary
+    PlatformFee { bps: u16 },
+    CreationFee { token: Asset, amount: U128 },
+    PriceOracle { oracle_id: Option<AccountId>, asset_id: Option<String>, max_age_sec: Option<U64> },
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ConfigChangeProposal {
+    pub id: U64,
+    pub change: ConfigChange,
+    pub proposed_at: U64,
+    pub executable_at: U64,
+}
+
+impl ConfigChange {
+    fn assert_valid(&self) {
+        if let ConfigChange::PlatformFee { bps } = self {
+            assert!(
+                u128::from(*bps) <= BPS_DENOMINATOR,
+                "Platform fee can't be more than {} bps",
+                BPS_DENOMINATOR
+            );
+        }
+    }
+}
+
+#[near_bindgen]
+impl RafflesMap {
+    #[private]
+    pub fn propose_config_change(&mut self, change: ConfigChange) -> U64 {
+        change.assert_valid();
+        let id = self.config_change_counter;
+        self.config_change_counter += 1;
+        let now = block_timestamp_ms();
+        self.config_changes.insert(
+            &id,
+            &ConfigChangeProposal {
+                id: U64(id),
+                change,
+                proposed_at: U64(now),
+                executable_at: U64(now + CONFIG_TIMELOCK_MS),
+            },
+        );
+        U64(id)
+    }
+
+    #[private]
+    pub fn cancel_config_change(&mut self, id: U64) {
+        self.config_changes.remove(&id.0).expect("No such config change");
+    }
+
+    // Applies a proposed change once its timelock is over, anyone can call it
+    pub fn execute_config_change(&mut self, id: U64) -> ConfigChange {
+        let id = id.0;
+        let proposal = self.config_changes.get(&id).expect("No such config change");
+        assert!(
+            block_timestamp_ms() >= proposal.executable_at.0,
+            "Config change {} is locked until {}",
+            id,
+            proposal.executable_at.0
+        );
+        self.config_changes.remove(&id);
+
+        match proposal.change.clone() {
+            ConfigChange::Beneficiary { account_id } => self.beneficiary = account_id,
+            ConfigChange::PlatformFee { bps } => self.config.platform_fee_bps = bps,
+            ConfigChange::CreationFee { token, amount } => {
+                self.config.fee_token = token;
+                self.config.creation_fee = amount.0;
+            }
+            ConfigChange::PriceOracle { oracle_id, asset_id, max_age_sec } => {
+                self.oracle = OracleConfig {
+                    oracle_id,
+                    asset_id: asset_id.unwrap_or_else(|| DEFAULT_NEAR_ASSET_ID.to_string()),
+                    max_age_sec: max_age_sec.unwrap_or(U64(DEFAULT_MAX_PRICE_AGE_SEC)),
+                };
+            }
+        }
+        proposal.change
+    }
+
+    // Changes waiting for their timelock, oldest first
+    pub fn get_config_changes(&self) -> Vec<ConfigChangeProposal> {
+        let mut proposals = self.config_changes.values_as_vector().to_vec();
+        proposals.sort_by_key(|proposal| proposal.id.0);
+        proposals
+    }
+}
+
+impl RafflesMap {
+    // pays the raffle's platform fee out of its revenue, see `split_revenue`
+    pub(crate) fn collect_platform_fee(&mut self, raffle: &mut Raffle) {
+        let fee = raffle.revenue * u128::from(raffle.platform_fee_bps) / BPS_DENOMINATOR;
+        raffle.revenue -= fee;
+        let beneficiary = self.beneficiary.clone();
+        self.credit(&beneficiary, Asset::Near, fee);
+    }
+}
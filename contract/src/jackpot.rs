@@ -0,0 +1,139 @@
+use near_sdk::json_types::Base64VecU8;
+
+use crate::*;
+
+/*
+ * Progressive jackpot shared by all raffles. `contribution_bps` of a raffle's revenue (after
+ * referral rewards) goes to the jackpot when its first prize is delivered or returned, once the
+ * raffle can't be aborted and refunded anymore. The rate is copied into raffles on creation
+ * like the referral share. Once every prize is settled, a number out of the random seed of the
+ * raffle's draw decides whether the first winner also gets the jackpot: `roll < chance_bps` out
+ * of 10000. Raffles without NFTs roll at the end of their draw, for the pot winner. The draw's
+ * block height and seed are kept with the win, so the roll can be recomputed from the chain.
+ */
+
+// hashed with the draw's seed, so the roll doesn't reuse the numbers that picked the winners
+const JACKPOT_SEED_SUFFIX: &[u8] = b"jackpot";
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Jackpot {
+    amount: u128,
+    contribution_bps: u16,
+    chance_bps: u16,
+    history: Vector<JackpotWin>,
+}
+
+impl Default for Jackpot {
+    fn default() -> Self {
+        Jackpot {
+            amount: 0,
+            contribution_bps: 0,
+            chance_bps: 0,
+            history: Vector::new(StorageKey::JackpotHistory),
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct JackpotWin {
+    pub raffle_id: U128,
+    pub winner: AccountId,
+    pub amount: U128,
+    pub roll: U64,
+    pub timestamp: U64,
+    // the raffle's draw, the roll comes from `sha256(random_seed ++ "jackpot")`
+    pub block_height: U64,
+    pub random_seed: Base64VecU8,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct JackpotView {
+    pub amount: U128,
+    pub contribution_bps: u16,
+    pub chance_bps: u16,
+    pub wins: U64,
+}
+
+#[near_bindgen]
+impl RafflesMap {
+    // 0 `contribution_bps` stops feeding the jackpot, 0 `chance_bps` stops paying it out
+    #[private]
+    pub fn set_jackpot(&mut self, contribution_bps: u16, chance_bps: u16) {
+        for bps in [contribution_bps, chance_bps] {
+            assert!(
+                u128::from(bps) <= BPS_DENOMINATOR,
+                "Jackpot bps can't be more than {}",
+                BPS_DENOMINATOR
+            );
+        }
+        self.jackpot.contribution_bps = contribution_bps;
+        self.jackpot.chance_bps = chance_bps;
+    }
+
+    pub fn get_jackpot(&self) -> JackpotView {
+        JackpotView {
+            amount: U128(self.jackpot.amount),
+            contribution_bps: self.jackpot.contribution_bps,
+            chance_bps: self.jackpot.chance_bps,
+            wins: U64(self.jackpot.history.len()),
+        }
+    }
+
+    // Past jackpot wins, oldest first
+    pub fn get_jackpot_history(&self, from_index: U64, limit: U64) -> Vec<JackpotWin> {
+        self.jackpot
+            .history
+            .iter()
+            .skip(from_index.0 as usize)
+            .take(limit.0 as usize)
+            .collect()
+    }
+}
+
+impl RafflesMap {
+    pub(crate) fn jackpot_contribution_bps(&self) -> u16 {
+        self.jackpot.contribution_bps
+    }
+
+    // takes the raffle's share out of its revenue, see `split_revenue`
+    pub(crate) fn feed_jackpot(&mut self, raffle: &mut Raffle) {
+        let contribution = raffle.revenue * u128::from(raffle.jackpot_bps) / BPS_DENOMINATOR;
+        raffle.revenue -= contribution;
+        self.jackpot.amount += contribution;
+    }
+
+    // Rolls for the jackpot once a raffle is settled, returns whether `winner` got it
+    pub(crate) fn roll_jackpot(&mut self, key: u128, winner: &AccountId, draw: &DrawReceipt) -> bool {
+        if self.jackpot.amount == 0 || self.jackpot.chance_bps == 0 {
+            return false;
+        }
+        let roll = jackpot_roll(draw);
+        if roll >= u64::from(self.jackpot.chance_bps) {
+            return false;
+        }
+
+        let win = JackpotWin {
+            raffle_id: U128(key),
+            winner: winner.clone(),
+            amount: U128(self.jackpot.amount),
+            roll: U64(roll),
+            timestamp: U64(block_timestamp_ms()),
+            block_height: draw.block_height,
+            random_seed: draw.random_seed.clone(),
+        };
+        log!("Jackpot of {} goes to {} in raffle {}", self.jackpot.amount, winner, key);
+        self.credit(winner, Asset::Near, self.jackpot.amount);
+        self.record_win(winner, self.jackpot.amount, 0);
+        self.jackpot.amount = 0;
+        self.jackpot.history.push(&win);
+        emit_event(EventLogVariant::JackpotWon(vec![win]));
+        true
+    }
+}
+
+pub(crate) fn jackpot_roll(draw: &DrawReceipt) -> u64 {
+    let seed = env::sha256(&[draw.random_seed.0.as_slice(), JACKPOT_SEED_SUFFIX].concat());
+    Rng::new(&seed).rand_range_u64(0, BPS_DENOMINATOR as u64)
+}
@@ -0,0 +1,120 @@
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+
+use crate::*;
+
+/*
+ * Every payout out of the money the contract holds (creator revenue, royalties, pots, referral
+ * rewards, refunds of aborted raffles) is credited here first and pulled with `withdraw`.
+ * A failed transfer puts the amount back, so a deleted account can't make funds disappear.
+ * Balances can also be topped up (`deposit`, or `ft_transfer_call` with "deposit" as msg) to pay
+ * creation fees.
+ */
+
+#[ext_contract(ft_contract)]
+pub trait FTContract {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum Asset {
+    Near,
+    // NEP-141 token contract
+    Ft(AccountId),
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct LedgerKey {
+    account_id: AccountId,
+    asset: Asset,
+}
+
+#[near_bindgen]
+impl RafflesMap {
+    #[payable]
+    pub fn deposit(&mut self) -> U128 {
+        let account_id = env::predecessor_account_id();
+        self.credit(&account_id, Asset::Near, env::attached_deposit());
+        self.balance_of(account_id, Asset::Near)
+    }
+
+    pub fn balance_of(&self, account_id: AccountId, token: Asset) -> U128 {
+        U128(self.ledger.get(&LedgerKey { account_id, asset: token }).unwrap_or(0))
+    }
+
+    // Sends the caller's whole balance of `token`
+    pub fn withdraw(&mut self, token: Asset) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let amount = self
+            .ledger
+            .remove(&LedgerKey { account_id: account_id.clone(), asset: token.clone() })
+            .expect("Nothing to withdraw");
+
+        let transfer = match &token {
+            Asset::Near => Promise::new(account_id.clone()).transfer(amount),
+            Asset::Ft(token_id) => ft_contract::ext(token_id.clone())
+                .with_static_gas(Gas(10 * TGAS))
+                .with_attached_deposit(1)
+                .ft_transfer(account_id.clone(), U128(amount), Some("Raffland withdrawal".to_string())),
+        };
+
+        transfer.then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(Gas(10 * TGAS))
+                .resolve_withdraw(account_id, token, U128(amount)),
+        )
+    }
+
+    #[private]
+    pub fn resolve_withdraw(
+        &mut self,
+        #[callback_result] call_result: Result<(), PromiseError>,
+        account_id: AccountId,
+        token: Asset,
+        amount: U128,
+    ) -> bool {
+        if call_result.is_err() {
+            log!("Withdrawal of {} to {} failed, balance restored", amount.0, account_id);
+            self.credit(&account_id, token, amount.0);
+            return false;
+        }
+        true
+    }
+}
+
+impl RafflesMap {
+    pub(crate) fn credit(&mut self, account_id: &AccountId, asset: Asset, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        let key = LedgerKey { account_id: account_id.clone(), asset };
+        let balance = self.ledger.get(&key).unwrap_or(0);
+        self.ledger.insert(&key, &(balance + amount));
+    }
+
+    pub(crate) fn debit(&mut self, account_id: &AccountId, asset: Asset, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        let key = LedgerKey { account_id: account_id.clone(), asset };
+        let balance = self.ledger.get(&key).unwrap_or(0);
+        assert!(balance >= amount, "{} has {} but {} is needed", account_id, balance, amount);
+        if balance == amount {
+            self.ledger.remove(&key);
+        } else {
+            self.ledger.insert(&key, &(balance - amount));
+        }
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for RafflesMap {
+    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128> {
+        if msg != "deposit" {
+            log!("Unknown msg {}, returning the tokens", msg);
+            return PromiseOrValue::Value(amount);
+        }
+        self.credit(&sender_id, Asset::Ft(env::predecessor_account_id()), amount.0);
+        PromiseOrValue::Value(U128(0))
+    }
+}
@@ -1,23 +1,70 @@
+// `#[near_bindgen]` copies method signatures into `ext` helpers without their attributes
+#![allow(clippy::too_many_arguments)]
+
 extern crate core;
 
-use std::borrow::BorrowMut;
+use std::collections::HashMap;
 use borsh::{self, BorshDeserialize, BorshSerialize};
-use near_contract_standards::non_fungible_token::{Token, TokenId};
+use near_contract_standards::non_fungible_token::approval::NonFungibleTokenApprovalReceiver;
+use near_contract_standards::non_fungible_token::metadata::NFTContractMetadata;
+use near_contract_standards::non_fungible_token::{NonFungibleToken, Token, TokenId};
 use near_rng::Rng;
-use near_sdk::collections::{UnorderedMap, UnorderedSet, Vector};
-use near_sdk::{AccountId, env, log, near_bindgen, Balance, Promise, PromiseError, Gas};
+use schemars::JsonSchema;
+use near_sdk::collections::{LookupMap, LookupSet, UnorderedMap, UnorderedSet, Vector};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{AccountId, BorshStorageKey, env, log, near_bindgen, Balance, Promise, PromiseError, PromiseOrValue, Gas};
 use near_sdk::env::block_timestamp_ms;
 use near_sdk::{
     serde::{Deserialize, Serialize}
 };
 use near_sdk::{ext_contract};
-use serde_json::json;
-use serde_json::Value;
+
+pub use crate::batch::*;
+pub use crate::claim::*;
+pub use crate::config::*;
+pub use crate::custody::*;
+pub use crate::events::*;
+pub use crate::governance::*;
+pub use crate::jackpot::*;
+pub use crate::ledger::*;
+pub use crate::metadata::*;
+pub use crate::oracle::*;
+pub use crate::pricing::*;
+pub use crate::prizes::*;
+pub use crate::prune::*;
+pub use crate::referral::*;
+pub use crate::reserve::*;
+pub use crate::series::*;
+pub use crate::staking::*;
+pub use crate::stats::*;
+pub use crate::tickets::*;
+
+mod batch;
+mod claim;
+mod config;
+mod custody;
+mod edit;
+mod events;
+mod governance;
+mod jackpot;
+mod ledger;
+mod metadata;
+mod migrate;
+mod oracle;
+mod pricing;
+mod prizes;
+mod prune;
+mod referral;
+mod reserve;
+mod series;
+mod staking;
+mod stats;
+mod tickets;
 
 
 // Validator interface, for cross-contract calls
 #[ext_contract(nft_contract)]
-trait NFTContract {
+pub trait NFTContract {
   fn nft_transfer(
         &mut self,
         receiver_id: AccountId,
@@ -28,6 +75,11 @@ trait NFTContract {
     );
 
     fn nft_token(&mut self, token_id: TokenId) -> Option<Token>;
+
+    fn nft_metadata(&self) -> NFTContractMetadata;
+
+    // NEP-199: how `balance` should be split between royalty holders and the owner
+    fn nft_payout(&self, token_id: TokenId, balance: U128, max_len_payout: Option<u32>) -> Payout;
 }
 // fn nft_transfer(
         //     &mut self,
@@ -37,7 +89,7 @@ trait NFTContract {
         // )
 
 
-/**
+/*
  * now only 1 winner
  * now only 1 prize
  * now start only now
@@ -46,30 +98,122 @@ trait NFTContract {
 pub const STORAGE_COST: u128 = 1_000_000_000_000_000_000_000;
 pub const ADD_PART_CALL_COST: u128 = 1_000_000_000_000_000_000_000;
 pub const MS_IN_HOUR: u64 = 3_600_000;
-const DEFAULT_COUNTER: u128 = 0;
-const DEFAULT_MESSAGE: &str = "Hello";
+// longest a raffle, a round, a reserve extension or a claim window can last
+pub const MAX_RAFFLE_HOURS: u64 = 365 * 24;
+const FIRST_RAFFLE_ID: u128 = 1;
 pub const TGAS: u64 = 1_000_000_000_000;
-
+pub const BPS_DENOMINATOR: u128 = 10_000;
+// royalty cap used when the creator doesn't set one (10%)
+pub const DEFAULT_MAX_ROYALTY_BPS: u16 = 1_000;
+// max number of accounts we accept from `nft_payout`
+pub const MAX_LEN_PAYOUT: u32 = 10;
+// gas attached per drawn prize: nft_transfer + resolve_prize_transfer
+pub const GAS_PER_PRIZE_DRAW: u64 = 40 * TGAS;
+// gas per refunded participant, it's only a ledger write
+pub const GAS_PER_REFUND: u64 = TGAS;
+// what a settlement step keeps for itself (state write, logs)
+pub const GAS_RESERVE: u64 = 20 * TGAS;
+pub const MAX_PRIZES_PER_STEP: u64 = 5;
+pub const MAX_PRIZES_PER_RAFFLE: usize = 10;
+// gas of one `nft_token` or `nft_metadata` view made to check a prize
+pub const GAS_PER_PRIZE_CHECK: u64 = 5 * TGAS;
+pub const MAX_REFUNDS_PER_STEP: u64 = 100;
+
+
+// prefixes of the collections nested into raffles and series
+#[derive(BorshSerialize, BorshStorageKey)]
+enum StorageKey {
+    Winners { raffle_id: u128 },
+    Participants { raffle_id: u128 },
+    Series,
+    SeriesTreasury { series_id: u64 },
+    Referrals { raffle_id: u128 },
+    Ledger,
+    OpenRaffles,
+    Blocklist,
+    RafflesByCategory,
+    RaffleCategory { category_hash: Vec<u8> },
+    TicketPrices { raffle_id: u128 },
+    PendingRaffleIds,
+    TicketOwners,
+    TicketMetadata,
+    TicketsPerOwner,
+    TicketApprovals,
+    StakingPools,
+    PoolRaffles,
+    JackpotHistory,
+    ConfigChanges,
+    StatsVolume,
+    StatsParticipants,
+    StatsCreators,
+    StatsWinners,
+    HeldPrizes,
+    TicketPayers { raffle_id: u128 },
+}
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct RafflesMap {
     raffles: UnorderedMap<u128, Raffle>,
-    counter: Counter,
+    // ids are taken when a raffle is requested, before its prizes are checked
+    next_raffle_id: u128,
+    // ids taken by `add_new_raffle` whose ownership check hasn't come back yet
+    pending_raffle_ids: LookupSet<u128>,
     pub beneficiary: AccountId,
-    greeting: String,
+    series: UnorderedMap<u64, RaffleSeries>,
+    series_counter: u64,
+    // share of a referred ticket paid to the referrer, copied into raffles on creation
+    referral_bps: u16,
+    // balances waiting for `withdraw`
+    ledger: LookupMap<LedgerKey, u128>,
+    config: Config,
+    // raffles each creator has running, series rounds aside
+    open_raffles: LookupMap<AccountId, u64>,
+    blocklist: UnorderedSet<AccountId>,
+    // ids of the raffles tagged with each category
+    raffles_by_category: LookupMap<String, UnorderedSet<u128>>,
+    // NEP-171 tickets of all raffles
+    tickets: NonFungibleToken,
+    // pools no-loss raffles may stake with
+    staking_pools: UnorderedSet<AccountId>,
+    // no-loss raffle each pool is staking for
+    pool_raffles: LookupMap<AccountId, u128>,
+    jackpot: Jackpot,
+    // NEAR price source of USD raffles
+    oracle: OracleConfig,
+    // timelocked changes of the settings above
+    config_changes: UnorderedMap<u64, ConfigChangeProposal>,
+    config_change_counter: u64,
+    // platform counters and leaderboards
+    stats: Stats,
+    // who deposited each NFT we hold and what it's a prize of, keyed by NFT contract and token id
+    held_prizes: LookupMap<(AccountId, TokenId), HeldPrize>,
 }
 
 impl Default for RafflesMap {
     fn default() -> Self {
         RafflesMap {
             raffles: UnorderedMap::new(b"m"),
-            counter: Counter {
-                value: DEFAULT_COUNTER,
-            },
-            //counter: Default::default(),
+            next_raffle_id: FIRST_RAFFLE_ID,
+            pending_raffle_ids: LookupSet::new(StorageKey::PendingRaffleIds),
             beneficiary: "v1.faucet.nonofficial.testnet".parse().unwrap(),
-            greeting: DEFAULT_MESSAGE.to_string(),
+            series: UnorderedMap::new(StorageKey::Series),
+            series_counter: 0,
+            referral_bps: 0,
+            ledger: LookupMap::new(StorageKey::Ledger),
+            config: Config::default(),
+            open_raffles: LookupMap::new(StorageKey::OpenRaffles),
+            blocklist: UnorderedSet::new(StorageKey::Blocklist),
+            raffles_by_category: LookupMap::new(StorageKey::RafflesByCategory),
+            tickets: new_tickets(),
+            staking_pools: UnorderedSet::new(StorageKey::StakingPools),
+            pool_raffles: LookupMap::new(StorageKey::PoolRaffles),
+            jackpot: Jackpot::default(),
+            oracle: OracleConfig::default(),
+            config_changes: UnorderedMap::new(StorageKey::ConfigChanges),
+            config_change_counter: 0,
+            stats: Stats::default(),
+            held_prizes: LookupMap::new(StorageKey::HeldPrizes),
         }
     }
 }
@@ -81,41 +225,58 @@ impl RafflesMap {
     pub fn init(beneficiary: AccountId) -> Self {
         assert!(!env::state_exists(), "Already initialized");
         Self {
-            beneficiary: beneficiary,
-            counter: Counter {
-                value: DEFAULT_COUNTER,
-            },
+            beneficiary,
             raffles: UnorderedMap::new(b"m"),
-            greeting: DEFAULT_MESSAGE.to_string(),
+            next_raffle_id: FIRST_RAFFLE_ID,
+            pending_raffle_ids: LookupSet::new(StorageKey::PendingRaffleIds),
+            series: UnorderedMap::new(StorageKey::Series),
+            series_counter: 0,
+            referral_bps: 0,
+            ledger: LookupMap::new(StorageKey::Ledger),
+            config: Config::default(),
+            open_raffles: LookupMap::new(StorageKey::OpenRaffles),
+            blocklist: UnorderedSet::new(StorageKey::Blocklist),
+            raffles_by_category: LookupMap::new(StorageKey::RafflesByCategory),
+            tickets: new_tickets(),
+            staking_pools: UnorderedSet::new(StorageKey::StakingPools),
+            pool_raffles: LookupMap::new(StorageKey::PoolRaffles),
+            jackpot: Jackpot::default(),
+            oracle: OracleConfig::default(),
+            config_changes: UnorderedMap::new(StorageKey::ConfigChanges),
+            config_change_counter: 0,
+            stats: Stats::default(),
+            held_prizes: LookupMap::new(StorageKey::HeldPrizes),
         }
     }
 
-    pub fn get_greeting(&self) -> String {
-        return self.greeting.clone();
-    }
-
-    pub fn get_counter(&self) -> &u128 {
-        &self.counter.value
-    }
-
-    pub fn get_counter1(&self) -> u128 {
-        return self.counter.value.clone();
+    // id the next requested raffle will get
+    pub fn next_raffle_id(&self) -> U128 {
+        U128(self.next_raffle_id)
     }
 
     fn get_raffle(&self, key: u128) -> Option<Raffle> {
         self.raffles.get(&key)
     }
 
-    pub fn get_winners(&self, key: &u128) -> Vec<Winner> {
-        self.raffles.get(key).unwrap().winners.to_vec()
+    pub fn get_winners(&self, key: U128) -> Vec<Winner> {
+        let key = key.0;
+        self.raffles.get(&key).unwrap().winners.to_vec()
     }
 
-    pub fn get_participants(&self, key: &u128) -> Vec<AccountId> {
-        self.raffles.get(key).unwrap().participants.to_vec()
+    pub fn get_participants(&self, key: U128) -> Vec<AccountId> {
+        let key = key.0;
+        self.raffles.get(&key).unwrap().participants.to_vec()
     }
 
-    fn get_random_participant(&self, key: &u128) -> Option<AccountId> {
-        self.raffles.get(key).unwrap().get_random_participant()
+    // ticket money held by the contract until the raffle is settled
+    pub fn get_revenue(&self, key: U128) -> U128 {
+        let key = key.0;
+        U128(self.raffles.get(&key).unwrap().revenue)
+    }
+
+    pub fn get_pot_winner(&self, key: U128) -> Option<AccountId> {
+        let key = key.0;
+        self.raffles.get(&key).unwrap().pot_winner
     }
 
     // TODO: get_random_prize
@@ -123,224 +284,805 @@ impl RafflesMap {
     //     self.raffles.get(key).unwrap().get_random_prize()
     // }
 
-    fn get_prize(&self, key: &u128, counter: u64) -> Option<JsonToken> {
-        self.raffles.get(key).unwrap().get_prize()
+    // Same as `buy_ticket_for` without a referrer, kept for older frontends
+    #[payable]
+    pub fn add_participant(&mut self, key: U128, sender: &AccountId) -> bool {
+        let key = key.0;
+        let payer = env::predecessor_account_id();
+        self.internal_add_participant(key, &payer, sender, None)
     }
 
+    // Buys a ticket for the caller; `referrer` earns a share of the ticket price once the raffle is settled
     #[payable]
-    pub fn add_participant(&mut self, key: u128, sender: &AccountId) -> bool {
-        // let sender: AccountId = env::predecessor_account_id();
-        let pays: Balance = env::attached_deposit();
-
-        let ticket_price: u128 = self.raffles.get(&key).unwrap().get_ticket_price();
+    pub fn buy_ticket(&mut self, key: U128, referrer: Option<AccountId>) -> bool {
+        let key = key.0;
+        let sender = env::predecessor_account_id();
+        self.internal_add_participant(key, &sender, &sender, referrer)
+    }
 
-        let to_transfer: Balance = if pays >= ticket_price {
-            // Subtract the storage cost to the amount to transfer
-            ticket_price - 1
-        } else {
-            0
-        };
+    // Gift: the caller pays and gets the change, `beneficiary` becomes the participant and gets
+    // the ticket. The one ticket per account limit applies to the beneficiary
+    #[payable]
+    pub fn buy_ticket_for(&mut self, key: U128, beneficiary: AccountId, referrer: Option<AccountId>) -> bool {
+        let key = key.0;
+        let payer = env::predecessor_account_id();
+        self.internal_add_participant(key, &payer, &beneficiary, referrer)
+    }
 
-        if to_transfer > 0 {
-            // Transfer the amount to the beneficiary
-            Promise::new(self.beneficiary.clone()).transfer(to_transfer);
-            let return_back: Balance = pays - ticket_price;
-            Promise::new(sender.clone()).transfer(return_back);
+    fn internal_add_participant(
+        &mut self,
+        key: u128,
+        payer: &AccountId,
+        beneficiary: &AccountId,
+        referrer: Option<AccountId>,
+    ) -> bool {
+        let current_raffle = self.raffles.get(&key).unwrap();
+        assert!(
+            current_raffle.pricing.usd().is_none(),
+            "Raffle {} is priced in USD, use buy_usd_ticket",
+            key
+        );
+        let ticket_price: u128 = current_raffle.current_price();
+        self.internal_sell_ticket(key, payer, beneficiary, referrer, env::attached_deposit(), ticket_price)
+    }
 
-            let mut current_raffle = self.raffles.get(&key).unwrap();
-            let mut participants = current_raffle.participants;
-            let participant_exist = participants.insert(sender);
-            if participant_exist {
-                current_raffle.participants = participants;
-                self.raffles.insert(&key, &current_raffle);
+    // Sells a ticket at `ticket_price` to `beneficiary`; `payer` paid `pays` and gets back what
+    // isn't used. The storage of the sale is paid on top of the price and stays with the contract
+    fn internal_sell_ticket(
+        &mut self,
+        key: u128,
+        payer: &AccountId,
+        beneficiary: &AccountId,
+        referrer: Option<AccountId>,
+        pays: Balance,
+        ticket_price: u128,
+    ) -> bool {
+        let mut current_raffle = self.raffles.get(&key).unwrap();
 
-                Promise::new(self.beneficiary.clone()).transfer(to_transfer);
-                let return_back: Balance = pays - ticket_price;
-                Promise::new(sender.clone()).transfer(return_back);
+        // participants are frozen once the raffle is over, the draw may take several transactions
+        let is_over = !*current_raffle.game_continues() || current_raffle.get_end_time() < &block_timestamp_ms();
+        let sold_out = current_raffle.max_tickets.is_some_and(|max| current_raffle.participants.len() >= max);
+        let cost = ticket_price + ticket_storage_cost();
+        if pays < cost || is_over || sold_out {
+            if pays > 0 {
+                Promise::new(payer.clone()).transfer(pays);
+            }
+            return false;
+        }
 
-                true
+        let participant_exist = current_raffle.participants.insert(beneficiary);
+        if participant_exist {
+            if let Some(no_loss) = current_raffle.no_loss.as_mut() {
+                // principal, staked instead of spent
+                no_loss.add_principal(ticket_price);
             } else {
-                Promise::new(sender.clone()).transfer(pays - ADD_PART_CALL_COST);
-                false
+                // ticket money stays on the contract until the draw, so royalties can be paid out of it
+                current_raffle.revenue += ticket_price;
             }
+            if current_raffle.pricing != Pricing::Fixed {
+                current_raffle.ticket_prices.insert(beneficiary, &ticket_price);
+            }
+            if let Some(referrer) = referrer.filter(|referrer| referrer != payer && referrer != beneficiary) {
+                current_raffle.add_referral(&referrer, ticket_price);
+            }
+            let ticket = current_raffle.participants.len() - 1;
+            current_raffle.ticket_payers.insert(&ticket, payer);
+            self.raffles.insert(&key, &current_raffle);
+            self.mint_ticket(key, ticket, beneficiary);
+            self.record_ticket_sold(current_raffle.get_creator(), beneficiary, ticket_price);
+            emit_event(EventLogVariant::TicketPurchased(vec![TicketPurchaseLog {
+                raffle_id: U128(key),
+                ticket: U64(ticket),
+                payer: payer.clone(),
+                beneficiary: beneficiary.clone(),
+                price: U128(ticket_price),
+            }]));
+
+            let return_back: Balance = pays - cost;
+            if return_back > 0 {
+                Promise::new(payer.clone()).transfer(return_back);
+            }
+
+            true
         } else {
+            let return_back: Balance = pays.saturating_sub(ADD_PART_CALL_COST);
+            if return_back > 0 {
+                Promise::new(payer.clone()).transfer(return_back);
+            }
             false
         }
     }
 
-    fn set_counter(&mut self, counter: u128) {
-        log!("Saving counter {}", counter);
-        self.counter.value = counter;
-    }
-
-    pub fn increment_counter(&mut self) {
-        self.counter.value += 1;
+    fn allocate_raffle_id(&mut self) -> u128 {
+        let key = self.next_raffle_id;
+        self.next_raffle_id += 1;
+        key
     }
 
-
+    // Results are the `nft_token` of every prize, then the `nft_metadata` of every prize collection
     #[private]
     pub fn check_token_ownership_and_finalize(
         &mut self,
-        #[callback_result] call_result: Result<Token, PromiseError>,
-        end_time: u64,
-        ticket_price: u128,
-        prizes: Vec<JsonToken>
+        key: u128,
+        args: NewRaffleArgs,
+        creator: AccountId,
+        fee: Fee,
     ) -> bool {
-        // Check if the promise succeeded by calling the method outlined in external.rs
-        if call_result.is_err() {
-            log!("There was an error contacting checking NFT ownership");
+        // the id stays unused if the raffle isn't created
+        assert!(self.pending_raffle_ids.remove(&key), "Raffle {} isn't pending", key);
+        let collections = prize_collections(&args.prizes);
+        assert_eq!(
+            env::promise_results_count(),
+            (args.prizes.len() + collections.len()) as u64,
+            "Unexpected number of prize checks"
+        );
+
+        let tokens: Vec<Option<Token>> = (0..args.prizes.len())
+            .map(|index| promise_result_json::<Option<Token>>(index as u64).flatten())
+            .collect();
+        let owned = args.prizes.iter().zip(tokens.iter()).all(|(prize, token)| {
+            matches!(token, Some(token)
+                if token.token_id == prize.token_id && token.owner_id == env::current_account_id())
+        });
+        if !owned {
+            log!("The contract doesn't own every prize, raffle isn't created");
+            self.uncommit_prizes(&args.prizes, key);
+            self.release_creation(&creator, fee);
             return false;
         }
 
+        let collections: HashMap<AccountId, NFTContractMetadata> = collections
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, collection)| {
+                promise_result_json((args.prizes.len() + index) as u64).map(|metadata| (collection, metadata))
+            })
+            .collect();
+        let prize_metadata = args
+            .prizes
+            .iter()
+            .zip(tokens.iter())
+            .map(|(prize, token)| {
+                token.as_ref().map(|token| PrizeMetadata::new(token, collections.get(&prize.owner_id)))
+            })
+            .collect();
+
+        self.internal_create_raffle(key, creator, args);
+        let mut raffle = self.raffles.get(&key).unwrap();
+        raffle.prize_metadata = prize_metadata;
+        self.raffles.insert(&key, &raffle);
+        self.collect_creation_fee(fee);
+        true
+    }
 
-        let result: Token = call_result.unwrap();
-        let owner_id: AccountId = result.owner_id;
-
-        if owner_id == env::current_account_id() {
-            self.increment_counter();
-
-        let winners: Vector<Winner> = Vector::new(b"t");
-        let participants: UnorderedSet<AccountId> = UnorderedSet::new(b"s");
-        let creator: AccountId = env::predecessor_account_id();
-
-        // calculate end time
-        let start = block_timestamp_ms();
-        let mut end = start + end_time * MS_IN_HOUR;
-        if end_time.eq(&end) {
-            end = start + 24 * MS_IN_HOUR;
-        }
+    fn internal_create_raffle(&mut self, key: u128, creator: AccountId, args: NewRaffleArgs) {
+        let NewRaffleArgs {
+            end_time,
+            ticket_price,
+            prizes,
+            max_royalty_bps,
+            max_tickets,
+            metadata,
+            pricing,
+            reserve,
+            claim_hours,
+        } = args;
+        let metadata = metadata.unwrap_or_default();
+        metadata.assert_valid();
+        let pricing = pricing.unwrap_or_default();
+        pricing.assert_valid(ticket_price);
+
+        assert!(self.raffles.get(&key).is_none(), "Raffle {} already exists", key);
+
+        let winners: Vector<Winner> = Vector::new(StorageKey::Winners { raffle_id: key });
+        let participants: UnorderedSet<AccountId> =
+            UnorderedSet::new(StorageKey::Participants { raffle_id: key });
+        let referrals: UnorderedMap<AccountId, Referral> =
+            UnorderedMap::new(StorageKey::Referrals { raffle_id: key });
 
         let new_raffle: Raffle = Raffle {
-            end_time: end,
-            prizes: prizes,
-            ticket_price: ticket_price,
+            end_time: end_time_from_hours(end_time),
+            prize_metadata: vec![None; prizes.len()],
+            prizes,
+            ticket_price,
             creator_wallet_account_id: creator,
             game_continues: true,
-            winners: winners,
-            participants: participants,
+            winners,
+            participants,
+            revenue: 0,
+            max_royalty_bps: max_royalty_bps.unwrap_or(DEFAULT_MAX_ROYALTY_BPS),
+            aborted: false,
+            refunded: 0,
+            series_id: None,
+            pot: 0,
+            pot_winner: None,
+            draw_started: false,
+            prizes_settled: 0,
+            revenue_split: false,
+            referral_bps: self.referral_bps,
+            jackpot_bps: self.jackpot_contribution_bps(),
+            platform_fee_bps: self.config.platform_fee_bps,
+            referrals,
+            referred_volume: 0,
+            max_tickets: max_tickets.filter(|max_tickets| *max_tickets > 0),
+            metadata,
+            pricing,
+            ticket_prices: LookupMap::new(StorageKey::TicketPrices { raffle_id: key }),
+            ticket_payers: LookupMap::new(StorageKey::TicketPayers { raffle_id: key }),
+            created_at: block_timestamp_ms(),
+            reserve,
+            extended: false,
+            claim_hours: claim_hours.filter(|hours| *hours > 0),
+            no_loss: None,
+            draw_receipt: None,
+            settled_at: None,
+            summary: None,
         };
 
-        let counter = *self.get_counter();
-            self.raffles.insert(&counter, &new_raffle);
-
-            return true;
-        } else {
-            return false;
-        } 
+        self.raffles.insert(&key, &new_raffle);
+        self.index_categories(key, &new_raffle.metadata);
+        self.record_raffle_created(&new_raffle.creator_wallet_account_id);
+        emit_event(EventLogVariant::RaffleCreated(vec![RaffleCreatedLog {
+            raffle_id: U128(key),
+            creator: new_raffle.creator_wallet_account_id.clone(),
+            prizes: new_raffle.prizes.clone(),
+            metadata: new_raffle.metadata.clone(),
+        }]));
     }
-    
 
+
+    // Attached deposit goes to the creator's balance, which pays the creation fee
+    #[payable]
     pub fn add_new_raffle(
-        &mut self, 
+        &mut self,
         // args: Base64VecU8
-        end_time: u64, 
-        ticket_price: u128, 
-        prizes: Vec<JsonToken>
+        end_time: U64,
+        ticket_price: U128,
+        prizes: Vec<JsonToken>,
+        max_royalty_bps: Option<u16>,
+        max_tickets: Option<U64>,
+        metadata: Option<RaffleMetadata>,
+        pricing: Option<Pricing>,
+        reserve: Option<Reserve>,
+        claim_hours: Option<U64>,
     ) -> Promise {
-        
-        //get ownder_id from prizes 
-        let nft_contract = prizes[0].owner_id.clone();
-        let nft_token_id = prizes[0].token_id.clone();
-        //near protocol this contract
-        let this_contract = env::current_account_id();
-
-        let st: String = "".parse().unwrap();
-
-        let promise = nft_contract::ext(nft_contract)
-            .with_static_gas(Gas(1*TGAS))
-            .nft_token(nft_token_id);
-        
-        return promise.then( // Create a promise to callback query_greeting_callback
+        let end_time = end_time.0;
+        let ticket_price = ticket_price.0;
+        let max_tickets = max_tickets.map(|max_tickets| max_tickets.0);
+        let claim_hours = claim_hours.map(|claim_hours| claim_hours.0);
+        let max_royalty_bps = assert_valid_royalty_bps(max_royalty_bps);
+        if let Some(metadata) = &metadata {
+            metadata.assert_valid();
+        }
+        if let Some(pricing) = &pricing {
+            pricing.assert_valid(ticket_price);
+        }
+        assert_valid_hours(&[Some(end_time), claim_hours, reserve.as_ref().and_then(Reserve::extension_hours)]);
+        let creator: AccountId = env::predecessor_account_id();
+        self.credit(&creator, Asset::Near, env::attached_deposit());
+        // prizes passed here are held by the contract itself, approvals only come via nft_on_approve
+        let prizes: Vec<JsonToken> = prizes
+            .into_iter()
+            .map(|prize| JsonToken { approval_id: None, ..prize })
+            .collect();
+        let fee = self.reserve_creation(&creator);
+        assert_valid_prizes(&prizes);
+        let key = self.allocate_raffle_id();
+        self.pending_raffle_ids.insert(&key);
+        self.commit_prizes(&prizes, &creator, PrizeCommitment::Raffle { raffle_id: U128(key) });
+
+        // every prize is checked on its own NFT contract, all answers come back to one callback
+        let checks = prizes
+            .iter()
+            .map(|prize| {
+                nft_contract::ext(prize.owner_id.clone())
+                    .with_static_gas(Gas(GAS_PER_PRIZE_CHECK))
+                    .nft_token(prize.token_id.clone())
+            })
+            .chain(prize_collections(&prizes).into_iter().map(|collection| {
+                nft_contract::ext(collection)
+                    .with_static_gas(Gas(GAS_PER_PRIZE_CHECK))
+                    .nft_metadata()
+            }))
+            .reduce(|checks, check| checks.and(check))
+            .unwrap();
+        let callback_gas = 10 * TGAS + prizes.len() as u64 * 2 * TGAS;
+
+        checks.then(
                 Self::ext(env::current_account_id())
-                .with_static_gas(Gas(1*TGAS))
+                .with_static_gas(Gas(callback_gas))
                 .check_token_ownership_and_finalize(
-                    end_time, 
-                    ticket_price, 
-                    prizes
+                    key,
+                    NewRaffleArgs {
+                        end_time,
+                        ticket_price,
+                        prizes,
+                        max_royalty_bps: Some(max_royalty_bps),
+                        max_tickets,
+                        metadata,
+                        pricing,
+                        reserve,
+                        claim_hours,
+                    },
+                    creator,
+                    fee
                 )
-            );
+            )
+    }
+
+    pub fn cancel_raffle(&mut self, key: U128) -> bool {
+        self.draw_step(key, U64(MAX_PRIZES_PER_STEP)).0 > 0
     }
 
-    fn cancel_raffle(&mut self, key: u128) -> bool {
-        if self.raffles.get(&key).unwrap().get_end_time() < &block_timestamp_ms() {
-            if self
-                .raffles
-                .get(&key)
-                .unwrap()
-                .get_participants()
-                .is_empty()
-            {
-                // TODO: send all prizes to creator
-                true
+    // Draws up to `max_prizes` of the remaining prizes and returns how many were drawn.
+    // Big raffles are settled by calling it again until `get_settlement_progress` says it's done
+    pub fn draw_step(&mut self, key: U128, max_prizes: U64) -> U64 {
+        let (key, max_prizes) = (key.0, max_prizes.0);
+        let mut current_raffle = self.get_raffle(key).unwrap();
+        if !current_raffle.game_continues() || current_raffle.get_end_time() >= &block_timestamp_ms() {
+            return U64(0);
+        }
+        // settled by `settle_no_loss`
+        if current_raffle.no_loss.is_some() {
+            return U64(0);
+        }
+
+        if let (true, Some(series_id)) = (current_raffle.get_participants().is_empty(), current_raffle.series_id) {
+            self.close_empty_round(key, series_id);
+            return U64(0);
+        }
+        if !current_raffle.draw_started && !current_raffle.reserve_met() {
+            self.handle_unmet_reserve(key, current_raffle);
+            return U64(0);
+        }
+
+        current_raffle.draw_started = true;
+
+        let prizes_total = current_raffle.get_prizes().len() as u64;
+        let mut drawn: u64 = 0;
+        let mut rng = Rng::new(&env::random_seed());
+        while current_raffle.winners.len() < prizes_total
+            && drawn < max_prizes.min(MAX_PRIZES_PER_STEP)
+            && remaining_gas() >= GAS_PER_PRIZE_DRAW + GAS_RESERVE
+        {
+            //TODO: что делать если остались лишние призы
+            let index = current_raffle.winners.len();
+            let prize = current_raffle.get_prizes()[index as usize].clone();
+
+            // take random winners and delete from collection participants
+            let ticket = current_raffle.get_random_ticket(&mut rng).unwrap();
+            let winner_account: AccountId = self.ticket_holder(key, &current_raffle, ticket);
+
+            // take random prize and sale false or delete fromm collection
+            // let prize: JsonToken = self.get_random_prize(&key).unwrap();
+
+            // add winners to winners
+            let mut winner: Winner = Winner {
+                winner_wallet_account_id: winner_account,
+                prize,
+                ticket: U64(ticket),
+                status: PrizeStatus::Claimable,
+                claim_deadline: None,
+                redraws: U64(0),
+            };
+            if current_raffle.claim_hours.is_some() {
+                winner.claim_deadline = Some(U64(current_raffle.claim_deadline()));
             } else {
-                for _x in 0..self.raffles.get(&key).unwrap().prizes.len() {
-                    //TODO: что делать если остались лишние призы
-
-                    // take random winners and delete from collection participants
-                    let winner_account: AccountId = self.get_random_participant(&key).unwrap();
-
-                    // take prize
-                    let prize: JsonToken = self.get_prize(&key, _x.try_into().unwrap()).unwrap();
-
-                    // take random prize and sale false or delete fromm collection
-                    // let prize: JsonToken = self.get_random_prize(&key).unwrap();
-
-                    // add winners to winners
-                    let winner: Winner = Winner {
-                        winner_wallet_account_id: winner_account,
-                        prize: prize,
-                    };
-                    self.add_winner(key, winner);
-
-                    // TODO: send price to winner
-                    // functional to do so
-                    // let promise = nft_contract::ext(nft_contract)
-                    // .with_static_gas(Gas(1*TGAS))
-                    // .with_attached_deposit(1)
-                    // .add_full_access_key(env::signer_account_pk())
-                    // .nft_transfer(
-                    //     this_contract,
-                    //     nft_token_id,
-                    //     Some(1u64),
-                    //     Some(st)
-                    // );
-                }
-                self.set_game_continues(false, key);
-                true
+                winner.status = PrizeStatus::Sending;
+                winner.send_prize_to_winner().then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(Gas(30 * TGAS))
+                        .resolve_prize_transfer(key, index),
+                );
             }
-        } else {
-            false
+            current_raffle.winners.push(&winner);
+            drawn += 1;
         }
-    }
 
-    fn add_winner(&mut self, key: u128, winner: Winner) {
-        let mut current_raffle = self.raffles.get(&key).unwrap();
-        let mut winners = current_raffle.winners;
-        winners.push(&winner);
-        current_raffle.winners = winners;
+        let finished = current_raffle.winners.len() == prizes_total;
+        if finished {
+            current_raffle.game_continues = false;
+            current_raffle.draw_receipt = Some(DrawReceipt::now());
+            if current_raffle.series_id.is_none() {
+                self.release_open_slot(current_raffle.get_creator());
+            }
+            if self.finish_draw(key, &mut current_raffle, &mut rng) {
+                drawn += 1;
+            }
+        }
         self.raffles.insert(&key, &current_raffle);
+
+        if let (true, Some(series_id)) = (finished, current_raffle.series_id) {
+            self.end_round(series_id);
+        }
+        U64(drawn)
     }
 
-    fn set_game_continues(&mut self, continues: bool, key: u128) {
-        let mut current_raffle = self.raffles.get(&key).unwrap();
-        current_raffle.game_continues = continues;
-        self.raffles.insert(&key, &current_raffle);
+    // Pays what doesn't depend on the NFT prizes once they are all drawn: the pot, and the
+    // revenue of raffles without NFTs, which are settled right away. Returns whether the pot was drawn
+    fn finish_draw(&mut self, key: u128, raffle: &mut Raffle, rng: &mut Rng) -> bool {
+        if raffle.prizes.is_empty() {
+            self.split_revenue(key, raffle);
+            self.credit(raffle.get_creator(), Asset::Near, raffle.revenue);
+            raffle.settled_at = Some(block_timestamp_ms());
+        }
+
+        if raffle.pot == 0 {
+            return false;
+        }
+        let ticket = raffle.get_random_ticket(rng).unwrap();
+        let pot_winner = self.ticket_holder(key, raffle, ticket);
+        self.credit(&pot_winner, Asset::Near, raffle.pot);
+        self.record_win(&pot_winner, raffle.pot, 0);
+        if raffle.prizes.is_empty() {
+            let draw = raffle.draw_receipt.clone().unwrap();
+            self.roll_jackpot(key, &pot_winner, &draw);
+        }
+        raffle.pot_winner = Some(pot_winner);
+        true
+    }
+
+    // Takes the referral, platform, jackpot and series shares out of the revenue. It's only done once
+    // the raffle can't be aborted anymore, an aborted raffle refunds every ticket in full
+    fn split_revenue(&mut self, key: u128, raffle: &mut Raffle) {
+        if raffle.revenue_split {
+            return;
+        }
+        raffle.revenue_split = true;
+        raffle.revenue -= raffle.referral_rewards_total();
+        self.collect_platform_fee(raffle);
+        self.feed_jackpot(raffle);
+        if let Some(series_id) = raffle.series_id {
+            raffle.revenue -= self.carve_next_pot(series_id, key, raffle.revenue);
+        }
+    }
+
+    // Counts a delivered or returned prize and pays out its share of the revenue. The jackpot is
+    // rolled for the first winner once every prize is settled
+    pub(crate) fn settle_prize(&mut self, key: u128, mut raffle: Raffle, index: u64) {
+        raffle.prizes_settled += 1;
+        self.split_revenue(key, &mut raffle);
+        if raffle.is_settled() {
+            raffle.settled_at = Some(block_timestamp_ms());
+        }
+        self.raffles.insert(&key, &raffle);
+        self.settle_prize_revenue(key, &raffle, index);
+        if raffle.is_settled() {
+            let first_winner = raffle.winners.get(0).unwrap().winner_wallet_account_id;
+            self.roll_jackpot(key, &first_winner, raffle.draw_receipt.as_ref().unwrap());
+        }
+    }
+
+    // Credits back up to `max_refunds` tickets of an aborted or settled no-loss raffle and returns how many were credited
+    pub fn refund_step(&mut self, key: U128, max_refunds: U64) -> U64 {
+        let (key, max_refunds) = (key.0, max_refunds.0);
+        let mut raffle = self.raffles.get(&key).unwrap();
+        if !raffle.is_refundable() {
+            return U64(0);
+        }
+
+        let mut refunded: u64 = 0;
+        while raffle.refunded < raffle.get_participants().len()
+            && refunded < max_refunds.min(MAX_REFUNDS_PER_STEP)
+            && remaining_gas() >= GAS_PER_REFUND + GAS_RESERVE
+        {
+            // the ticket may have changed hands, its holder gets what the buyer paid
+            let participant = raffle.get_participants().as_vector().get(raffle.refunded).unwrap();
+            let holder = self.ticket_holder(key, &raffle, raffle.refunded);
+            self.credit(&holder, Asset::Near, raffle.paid_by(&participant));
+            raffle.refunded += 1;
+            refunded += 1;
+        }
+        if raffle.refunded == raffle.get_participants().len() && raffle.settled_at.is_none() {
+            raffle.settled_at = Some(block_timestamp_ms());
+        }
+
+        self.raffles.insert(&key, &raffle);
+        U64(refunded)
+    }
+
+    pub fn get_settlement_progress(&self, key: U128) -> SettlementProgress {
+        let key = key.0;
+        let raffle = self.raffles.get(&key).unwrap();
+        SettlementProgress {
+            prizes_total: U64(raffle.get_prizes().len() as u64),
+            prizes_drawn: U64(raffle.get_winners().len()),
+            participants_total: U64(raffle.get_participants().len()),
+            refunded: U64(raffle.refunded),
+            game_continues: raffle.game_continues,
+            aborted: raffle.aborted,
+        }
+    }
+
+    #[private]
+    pub fn resolve_prize_transfer(
+        &mut self,
+        #[callback_result] call_result: Result<(), PromiseError>,
+        key: u128,
+        index: u64,
+    ) -> bool {
+        let mut raffle = self.raffles.get(&key).unwrap();
+        let mut winner = raffle.winners.get(index).unwrap();
+
+        if call_result.is_err() {
+            if winner.prize.approval_id.is_some() {
+                // approval was revoked or the token left the creator's wallet
+                log!("Prize {} of raffle {} is gone, aborting", winner.prize.token_id, key);
+                self.abort_raffle(key);
+                return false;
+            }
+            log!("Couldn't send prize {} to {}", winner.prize.token_id, winner.winner_wallet_account_id);
+            // the prize stays with us: the winner can claim it until the deadline,
+            // then it's redrawn or returned with `expire_prize`
+            if winner.status == PrizeStatus::Sending {
+                winner.claim_deadline = Some(U64(raffle.claim_deadline()));
+            }
+            winner.status = PrizeStatus::Claimable;
+            raffle.winners.replace(index, &winner);
+            return false;
+        }
+
+        winner.status = PrizeStatus::Delivered;
+        self.release_prize(&winner.prize);
+        self.record_win(&winner.winner_wallet_account_id, 0, 1);
+        raffle.winners.replace(index, &winner);
+        self.settle_prize(key, raffle, index);
+        true
+    }
+
+    // Ticket revenue is split between the prizes; each share is checked against the
+    // prize's NEP-199 royalties before the rest goes to the creator
+    fn settle_prize_revenue(&self, key: u128, raffle: &Raffle, index: u64) {
+        let prizes_count = raffle.get_prizes().len() as u128;
+        let share = raffle.revenue / prizes_count;
+        let dust = raffle.revenue - share * prizes_count;
+        let balance = if index == 0 { share + dust } else { share };
+        if balance == 0 {
+            return;
+        }
+
+        let winner = raffle.get_winners().get(index).unwrap();
+        nft_contract::ext(winner.prize.owner_id.clone())
+            .with_static_gas(Gas(10 * TGAS))
+            .nft_payout(winner.prize.token_id.clone(), U128(balance), Some(MAX_LEN_PAYOUT))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas(10 * TGAS))
+                    .resolve_payout(key, U128(balance), winner.winner_wallet_account_id),
+            );
+    }
+
+    // Gives every participant their ticket back, the prize never left its owner.
+    // The first batch goes out right away, the rest through `refund_step`
+    fn abort_raffle(&mut self, key: u128) {
+        let mut raffle = self.raffles.get(&key).unwrap();
+        raffle.revenue = 0;
+        raffle.aborted = true;
+        if raffle.game_continues {
+            raffle.game_continues = false;
+            self.release_open_slot(raffle.get_creator());
+        }
+        self.raffles.insert(&key, &raffle);
+
+        self.refund_step(U128(key), U64(MAX_REFUNDS_PER_STEP));
+    }
+
+    #[private]
+    pub fn resolve_payout(
+        &mut self,
+        #[callback_result] call_result: Result<Payout, PromiseError>,
+        key: u128,
+        balance: U128,
+        winner: AccountId,
+    ) -> U128 {
+        let raffle = self.raffles.get(&key).unwrap();
+        let creator = raffle.get_creator().clone();
+
+        // token without royalties support: everything goes to the creator
+        let payout = match call_result {
+            Ok(payout) => payout.payout,
+            Err(_) => {
+                log!("nft_payout failed for raffle {}, paying creator in full", key);
+                HashMap::new()
+            }
+        };
+
+        // the "owner" share of the payout belongs to the seller, which is us or the new owner
+        let mut excluded = vec![env::current_account_id(), winner];
+        excluded.push(creator.clone());
+
+        let (royalties, creator_share) =
+            split_payout(balance.0, &payout, raffle.max_royalty_bps, &excluded);
+
+        for (account, amount) in royalties.iter() {
+            log!("Royalty {} to {} for raffle {}", amount, account, key);
+            self.credit(account, Asset::Near, *amount);
+        }
+        self.credit(&creator, Asset::Near, creator_share);
+
+        U128(balance.0 - creator_share)
     }
 }
 
-// #[near_bindgen]
-#[derive(Eq, Default, Hash, PartialEq, PartialOrd, BorshDeserialize, BorshSerialize)]
-pub struct Counter {
-    value: u128,
+// Raffles where the prize stays in the creator's wallet: the creator calls `nft_approve` for this
+// contract with the raffle args in `msg`, and the token is moved with the approval at the draw
+#[near_bindgen]
+impl NonFungibleTokenApprovalReceiver for RafflesMap {
+    fn nft_on_approve(
+        &mut self,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
+        msg: String,
+    ) -> PromiseOrValue<String> {
+        assert_ne!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "Tickets can't be prizes"
+        );
+        let args: ApprovalRaffleArgs =
+            near_sdk::serde_json::from_str(&msg).expect("msg should contain the raffle args");
+        let max_royalty_bps = assert_valid_royalty_bps(args.max_royalty_bps);
+        // `owner_id` is whatever the calling contract says, the owner has to have signed the approval
+        assert_eq!(
+            env::signer_account_id(),
+            owner_id,
+            "Only the token owner can put it up as a prize"
+        );
+        assert_valid_hours(&[
+            Some(args.end_time.0),
+            args.claim_hours.map(|claim_hours| claim_hours.0),
+            args.reserve.as_ref().and_then(Reserve::extension_hours),
+        ]);
+
+        let fee = self.reserve_creation(&owner_id);
+
+        let prize = JsonToken {
+            token_id,
+            owner_id: env::predecessor_account_id(),
+            approval_id: Some(approval_id),
+        };
+        let key = self.allocate_raffle_id();
+        self.internal_create_raffle(
+            key,
+            owner_id,
+            NewRaffleArgs {
+                end_time: args.end_time.0,
+                ticket_price: args.ticket_price.0,
+                prizes: vec![prize],
+                max_royalty_bps: Some(max_royalty_bps),
+                max_tickets: args.max_tickets.map(|max_tickets| max_tickets.0),
+                metadata: args.metadata,
+                pricing: args.pricing,
+                reserve: args.reserve,
+                claim_hours: args.claim_hours.map(|claim_hours| claim_hours.0),
+            },
+        );
+        self.collect_creation_fee(fee);
+
+        PromiseOrValue::Value(key.to_string())
+    }
+}
+
+// end of a raffle lasting `hours` from now, 0 means a day
+fn end_time_from_hours(hours: u64) -> u64 {
+    let hours = if hours == 0 { 24 } else { hours };
+    hours
+        .checked_mul(MS_IN_HOUR)
+        .and_then(|duration| duration.checked_add(block_timestamp_ms()))
+        .expect("End time overflows")
+}
+
+// checked before anything is reserved, so end times and deadlines computed later can't overflow
+fn assert_valid_hours(hours: &[Option<u64>]) {
+    assert!(
+        hours.iter().flatten().all(|hours| *hours <= MAX_RAFFLE_HOURS),
+        "Durations can't be more than {} hours",
+        MAX_RAFFLE_HOURS
+    );
 }
 
+fn remaining_gas() -> u64 {
+    env::prepaid_gas().0.saturating_sub(env::used_gas().0)
+}
+
+fn assert_valid_royalty_bps(max_royalty_bps: Option<u16>) -> u16 {
+    let max_royalty_bps = max_royalty_bps.unwrap_or(DEFAULT_MAX_ROYALTY_BPS);
+    assert!(
+        u128::from(max_royalty_bps) <= BPS_DENOMINATOR,
+        "max_royalty_bps can't be more than {}",
+        BPS_DENOMINATOR
+    );
+    max_royalty_bps
+}
+
+// Takes the royalty part of `payout` (everything not owed to `excluded` accounts), scales it
+// down to `max_royalty_bps` of `balance` if needed and returns it with what's left for the creator
+fn split_payout(
+    balance: u128,
+    payout: &HashMap<AccountId, U128>,
+    max_royalty_bps: u16,
+    excluded: &[AccountId],
+) -> (Vec<(AccountId, u128)>, u128) {
+    let mut royalties: Vec<(AccountId, u128)> = payout
+        .iter()
+        .filter(|(account, amount)| !excluded.contains(account) && amount.0 > 0)
+        .map(|(account, amount)| (account.clone(), amount.0))
+        .collect();
+    royalties.sort();
+
+    let requested: u128 = royalties.iter().map(|(_, amount)| amount).sum();
+    let cap = balance * u128::from(max_royalty_bps) / BPS_DENOMINATOR;
+
+    if requested > cap {
+        for royalty in royalties.iter_mut() {
+            royalty.1 = royalty.1 * cap / requested;
+        }
+        royalties.retain(|(_, amount)| *amount > 0);
+    }
+
+    let paid: u128 = royalties.iter().map(|(_, amount)| amount).sum();
+    (royalties, balance - paid)
+}
+
+// what add_new_raffle passes on to check_token_ownership_and_finalize
 // #[near_bindgen]
-#[derive(Deserialize, Serialize, BorshDeserialize, BorshSerialize, Debug)]
+#[derive(Deserialize, Serialize, JsonSchema, BorshDeserialize, BorshSerialize, Debug)]
 #[serde(crate = "near_sdk::serde")]
 pub struct NewRaffleArgs {
     end_time: u64,
     ticket_price: u128,
     prizes: Vec<JsonToken>,
+    max_royalty_bps: Option<u16>,
+    #[serde(default)]
+    max_tickets: Option<u64>,
+    #[serde(default)]
+    metadata: Option<RaffleMetadata>,
+    #[serde(default)]
+    pricing: Option<Pricing>,
+    #[serde(default)]
+    reserve: Option<Reserve>,
+    // winners have to claim their prizes within this many hours
+    #[serde(default)]
+    claim_hours: Option<u64>,
+}
+
+// `msg` of `nft_approve` for approval raffles
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ApprovalRaffleArgs {
+    end_time: U64,
+    ticket_price: U128,
+    max_royalty_bps: Option<u16>,
+    #[serde(default)]
+    max_tickets: Option<U64>,
+    #[serde(default)]
+    metadata: Option<RaffleMetadata>,
+    #[serde(default)]
+    pricing: Option<Pricing>,
+    #[serde(default)]
+    reserve: Option<Reserve>,
+    // winners have to claim their prizes within this many hours
+    #[serde(default)]
+    claim_hours: Option<U64>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SettlementProgress {
+    pub prizes_total: U64,
+    pub prizes_drawn: U64,
+    pub participants_total: U64,
+    pub refunded: U64,
+    pub game_continues: bool,
+    pub aborted: bool,
+}
+
+// NEP-199 payout, as returned by `nft_payout`
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Payout {
+    pub payout: HashMap<AccountId, U128>,
 }
 
 // #[near_bindgen]
@@ -350,11 +1092,62 @@ pub struct Raffle {
     //TODO: end time or continues
     end_time: u64,
     prizes: Vec<JsonToken>,
+    // cached `nft_token`/`nft_metadata` of each prize, None when the prize wasn't looked up
+    prize_metadata: Vec<Option<PrizeMetadata>>,
     ticket_price: u128,
     creator_wallet_account_id: AccountId,
     game_continues: bool,
     winners: Vector<Winner>,
     participants: UnorderedSet<AccountId>,
+    // collected ticket money, paid out on settlement
+    revenue: u128,
+    // max share of the revenue that can go to NFT royalties, in basis points
+    max_royalty_bps: u16,
+    // prize couldn't be delivered and tickets were refunded
+    aborted: bool,
+    // how many participants of an aborted raffle got their ticket back
+    refunded: u64,
+    // set for rounds opened by a raffle series
+    series_id: Option<u64>,
+    // NEAR prize on top of the NFTs, goes to one more random participant
+    pot: u128,
+    pot_winner: Option<AccountId>,
+    // first draw step has been made
+    draw_started: bool,
+    // prizes whose transfer came back without aborting the raffle
+    prizes_settled: u64,
+    // referral, platform, jackpot and series shares were taken out of `revenue`, see `split_revenue`
+    revenue_split: bool,
+    referral_bps: u16,
+    // share of the revenue fed to the jackpot
+    jackpot_bps: u16,
+    // share of the revenue paid to the beneficiary
+    platform_fee_bps: u16,
+    referrals: UnorderedMap<AccountId, Referral>,
+    // money paid for referred tickets
+    referred_volume: u128,
+    max_tickets: Option<u64>,
+    metadata: RaffleMetadata,
+    pricing: Pricing,
+    // what each participant paid, only kept when the price isn't fixed
+    ticket_prices: LookupMap<AccountId, u128>,
+    // who paid each ticket and its storage, credited for the storage when the raffle is pruned
+    ticket_payers: LookupMap<u64, AccountId>,
+    created_at: u64,
+    // least revenue the creator accepts for the prizes
+    reserve: Option<Reserve>,
+    // the end was pushed back once because the reserve wasn't met
+    extended: bool,
+    // prizes wait for `claim_prize` instead of being sent
+    claim_hours: Option<u64>,
+    // ticket money is staked and only the reward is raffled
+    no_loss: Option<NoLoss>,
+    // randomness of the draw step that finished the draw
+    draw_receipt: Option<DrawReceipt>,
+    // when the last prize was settled or the last refund credited
+    settled_at: Option<u64>,
+    // set once pruning starts
+    summary: Option<RaffleSummary>,
 }
 
 // #[near_bindgen]
@@ -368,11 +1161,11 @@ impl Raffle {
     // }
 
     fn get_ticket_price(&self) -> u128 {
-        return self.ticket_price;
+        self.ticket_price
     }
 
     fn get_creator(&self) -> &AccountId {
-        return &self.creator_wallet_account_id;
+        &self.creator_wallet_account_id
     }
 
     fn get_participants(&self) -> &UnorderedSet<AccountId> {
@@ -384,17 +1177,15 @@ impl Raffle {
     }
 
     fn get_prizes(&self) -> &Vec<JsonToken> {
-        return &self.prizes;
-    }
-
-    fn get_prize(&mut self) -> Option<JsonToken> {
-        self.prizes.pop()
+        &self.prizes
     }
 
-    fn get_random_participant(&self) -> Option<AccountId> {
-        let mut rng = Rng::new(&env::random_seed());
-        let random_number = rng.rand_range_u64(0, self.participants.len());
-        self.participants.as_vector().get(random_number)
+    // ticket numbers are indexes of the participants
+    fn get_random_ticket(&self, rng: &mut Rng) -> Option<u64> {
+        if self.participants.is_empty() {
+            return None;
+        }
+        Some(rng.rand_range_u64(0, self.participants.len()))
     }
 
     // fn get_random_winner(&self) -> &AccountId {
@@ -405,32 +1196,54 @@ impl Raffle {
         &self.game_continues
     }
 
+    // every prize is drawn and delivered, so nothing can be refunded anymore
+    fn is_settled(&self) -> bool {
+        !self.game_continues && !self.aborted && self.prizes_settled == self.prizes.len() as u64
+    }
+
     fn get_end_time(&self) -> &u64 {
         &self.end_time
     }
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, Debug)]
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
 #[serde(crate = "near_sdk::serde")]
 pub struct JsonToken {
     pub token_id: TokenId,
     pub owner_id: AccountId,
+    // set when the prize stays with the creator and we only hold an approval for it
+    #[serde(default)]
+    pub approval_id: Option<u64>,
 }
 
 // #[near_bindgen]
-#[derive(Deserialize, Serialize, BorshDeserialize, BorshSerialize, Debug)]
+#[derive(Deserialize, Serialize, JsonSchema, BorshDeserialize, BorshSerialize, Debug)]
 // #[serde(crate = "near_sdk::serde")]
 pub struct Winner {
     winner_wallet_account_id: AccountId,
     prize: JsonToken,
+    // winning ticket, `winner_wallet_account_id` held it at the draw
+    ticket: U64,
+    status: PrizeStatus,
+    // set while the prize waits for a claim: after it the prize can be redrawn
+    claim_deadline: Option<U64>,
+    // winners before this one who didn't claim the prize
+    redraws: U64,
 }
 
 // #[near_bindgen]
 impl Winner {
-    //TODO: send prize to winner
-    fn send_prize_to_winner(&mut self) {
-        // cross contract call nft
-        // method take my price (backend)
+    // the prize is either kept by this contract or approved to it, so we can transfer it ourselves
+    fn send_prize_to_winner(&self) -> Promise {
+        nft_contract::ext(self.prize.owner_id.clone())
+            .with_static_gas(Gas(10 * TGAS))
+            .with_attached_deposit(1)
+            .nft_transfer(
+                self.winner_wallet_account_id.clone(),
+                self.prize.token_id.clone(),
+                self.prize.approval_id,
+                Some("Raffland prize".to_string()),
+            )
     }
 }
 
@@ -439,169 +1252,1586 @@ mod tests {
     use super::*;
     use near_sdk::test_utils::VMContextBuilder;
     use near_sdk::testing_env;
-    use near_sdk::Balance;
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenReceiver;
+    use near_contract_standards::non_fungible_token::enumeration::NonFungibleTokenEnumeration;
+    use near_contract_standards::non_fungible_token::metadata::TokenMetadata;
+    use near_sdk::{PromiseResult, RuntimeFeesConfig, VMConfig};
+    use crate::migrate::{BaselineRaffle, BaselineRafflesMap};
 
     const BENEFICIARY: &str = "beneficiary";
     const NEAR: u128 = 1000000000000000000000000;
 
+    fn set_context(predecessor: &str, deposit: Balance, timestamp_ms: u64) {
+        let mut builder = VMContextBuilder::new();
+        builder.predecessor_account_id(predecessor.parse().unwrap());
+        builder.attached_deposit(deposit);
+        builder.block_timestamp(timestamp_ms * 1_000_000);
+        testing_env!(builder.build());
+    }
+
+    // nft.near calling back after carol.near approved us
+    fn set_approval_context(timestamp_ms: u64) {
+        let mut builder = VMContextBuilder::new();
+        builder.predecessor_account_id("nft.near".parse().unwrap());
+        builder.signer_account_id("carol.near".parse().unwrap());
+        builder.block_timestamp(timestamp_ms * 1_000_000);
+        testing_env!(builder.build());
+    }
+
+    // deposit buying a ticket at `price`
+    fn with_storage(price: Balance) -> Balance {
+        price + ticket_storage_cost()
+    }
+
+    // keeps the context but makes callbacks see `results` as the answers of their promises
+    fn set_promise_results(results: Vec<PromiseResult>) {
+        let mut builder = VMContextBuilder::new();
+        builder.predecessor_account_id(env::predecessor_account_id());
+        builder.attached_deposit(env::attached_deposit());
+        builder.block_timestamp(env::block_timestamp());
+        testing_env!(builder.build(), VMConfig::test(), RuntimeFeesConfig::test(), Default::default(), results);
+    }
+
+    fn json_result<T: Serialize>(value: &T) -> PromiseResult {
+        PromiseResult::Successful(near_sdk::serde_json::to_vec(value).unwrap())
+    }
+
+    // proposes `change` and executes it once the timelock is over
+    fn apply_config_change(contract: &mut RafflesMap, change: ConfigChange) {
+        set_context("alice.near", 0, 0);
+        let id = contract.propose_config_change(change);
+        set_context("alice.near", 0, CONFIG_TIMELOCK_MS);
+        contract.execute_config_change(id);
+    }
+
+    fn owned_token(token_id: &str) -> Token {
+        Token {
+            token_id: token_id.to_string(),
+            owner_id: env::current_account_id(),
+            metadata: None,
+            approved_account_ids: None,
+        }
+    }
+
+    // what add_new_raffle does before scheduling the ownership checks
+    fn reserve_raffle_id(contract: &mut RafflesMap) -> u128 {
+        let key = contract.allocate_raffle_id();
+        contract.pending_raffle_ids.insert(&key);
+        key
+    }
+
+    // `depositor` sends the prizes to the contract with nft_transfer_call
+    fn deposit_prizes(contract: &mut RafflesMap, depositor: &str, prizes: &[JsonToken]) {
+        let (caller, deposit, now) = (env::predecessor_account_id(), env::attached_deposit(), block_timestamp_ms());
+        for prize in prizes {
+            set_context(prize.owner_id.as_str(), 0, now);
+            let depositor: AccountId = depositor.parse().unwrap();
+            contract.nft_on_transfer(depositor.clone(), depositor, prize.token_id.clone(), String::new());
+        }
+        set_context(caller.as_str(), deposit, now);
+    }
+
+    // add_new_raffle only schedules the ownership checks, so tests run its callback directly
+    fn new_raffle(contract: &mut RafflesMap, end_time: u64, ticket_price: u128, prizes: Vec<JsonToken>) -> bool {
+        deposit_prizes(contract, env::predecessor_account_id().as_str(), &prizes);
+        let mut results: Vec<PromiseResult> =
+            prizes.iter().map(|prize| json_result(&Some(owned_token(&prize.token_id)))).collect();
+        results.extend(prize_collections(&prizes).iter().map(|_| PromiseResult::Failed));
+        set_promise_results(results);
+
+        let creator = env::predecessor_account_id();
+        let fee = contract.reserve_creation(&creator);
+        let key = reserve_raffle_id(contract);
+        contract.commit_prizes(&prizes, &creator, PrizeCommitment::Raffle { raffle_id: U128(key) });
+        let args = NewRaffleArgs { end_time, ticket_price, prizes, max_royalty_bps: None, max_tickets: None, metadata: None, pricing: None, reserve: None, claim_hours: None };
+        contract.check_token_ownership_and_finalize(key, args, creator, fee)
+    }
+
+    // state of the first deployed version with one finished raffle, and one still running if asked
+    fn write_baseline_state(running: bool) {
+        let mut raffles = UnorderedMap::new(b"m");
+        for (key, game_continues) in [(1u128, false), (2, running)] {
+            let mut participants = UnorderedSet::new(b"s");
+            participants.insert(&"bob.near".parse().unwrap());
+            let raffle = BaselineRaffle {
+                end_time: 0,
+                prizes: vec![("1".to_string(), "nft.near".parse().unwrap())],
+                ticket_price: 1,
+                creator_wallet_account_id: "carol.near".parse().unwrap(),
+                game_continues,
+                winners: Vector::new(b"t"),
+                participants,
+            };
+            raffles.insert(&key, &raffle);
+        }
+        env::state_write(&BaselineRafflesMap {
+            raffles,
+            counter: 2,
+            beneficiary: BENEFICIARY.parse().unwrap(),
+            greeting: "Hello".to_string(),
+        });
+    }
+
+    #[test]
+    fn migrate_from_baseline() {
+        write_baseline_state(false);
+        // first participant of the set all baseline raffles shared
+        let participant_key = [b"se".as_slice(), &0u64.to_le_bytes()].concat();
+        assert!(env::storage_has_key(&participant_key));
+
+        let contract = RafflesMap::migrate();
+        assert_eq!(contract.beneficiary.as_str(), BENEFICIARY);
+        assert_eq!(contract.next_raffle_id().0, 3);
+        assert_eq!(contract.raffles.len(), 0);
+        assert!(!env::storage_has_key(&participant_key));
+    }
+
+    #[test]
+    #[should_panic(expected = "Every raffle has to be drawn before the upgrade")]
+    fn migrate_waits_for_running_raffles() {
+        write_baseline_state(true);
+        RafflesMap::migrate();
+    }
+
     #[test]
-    fn get_default_counter() {
+    fn get_default_next_raffle_id() {
         // let contract = RafflesMap::default();
         let contract = RafflesMap::init(BENEFICIARY.parse().unwrap());
-        assert_eq!(contract.get_counter().clone(), 0);
-        // assert_eq!(contract.get_counter(), 0);
+        assert_eq!(contract.next_raffle_id().0, 1);
     }
 
     #[test]
     fn random() {
         let mut rng = Rng::new(&env::random_seed());
-        let random_number1 = rng.rand_range_u64(0, 10);
-        let random_number2 = rng.rand_range_u64(0, 10);
-        // only sometime true
+        let mut rng1 = Rng::new(&env::random_seed());
+        let random_number = rng.rand_range_u64(0, 10);
+        let random_number1 = rng1.rand_range_u64(0, 10);
+        // same seed gives the same draw
         assert_eq!(random_number, random_number1);
+        assert!(random_number < 10);
     }
 
     #[test]
-    fn set_then_get_counter() {
+    fn failed_raffle_keeps_its_id() {
         // let mut contract = RafflesMap::default();
         let mut contract = RafflesMap::init(BENEFICIARY.parse().unwrap());
-        assert_eq!(contract.get_counter().clone(), 0);
-        contract.set_counter(1);
-        assert_eq!(contract.get_counter().clone(), 1);
+        set_context("bob.near", 0, 0);
+        let prize = |token_id: &str| JsonToken { token_id: token_id.to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None };
+        deposit_prizes(&mut contract, "bob.near", &[prize("1111"), prize("2222")]);
+        contract.add_new_raffle(U64(1), U128(1), vec![prize("1111")], None, None, None, None, None, None);
+        contract.add_new_raffle(U64(1), U128(1), vec![prize("2222")], None, None, None, None, None, None);
+        assert_eq!(contract.next_raffle_id().0, 3);
+
+        // the first check fails, the second raffle still gets id 2
+        let args = || NewRaffleArgs {
+            end_time: 1,
+            ticket_price: 1,
+            prizes: vec![JsonToken { token_id: "1111".to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None }],
+            max_royalty_bps: None,
+            max_tickets: None,
+            metadata: None,
+            pricing: None,
+            reserve: None,
+            claim_hours: None,
+        };
+        let creator: AccountId = "bob.near".parse().unwrap();
+        let fee = contract.creation_fee();
+        set_promise_results(vec![PromiseResult::Failed, PromiseResult::Failed]);
+        assert!(!contract.check_token_ownership_and_finalize(1, args(), creator.clone(), fee.clone()));
+        set_promise_results(vec![json_result(&Some(owned_token("1111"))), PromiseResult::Failed]);
+        assert!(contract.check_token_ownership_and_finalize(2, args(), creator, fee));
+        assert!(contract.get_raffle_view(U128(1)).is_none());
+        assert!(contract.get_raffle_view(U128(2)).is_some());
+        assert_eq!(contract.next_raffle_id().0, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "was deposited by someone else")]
+    fn cant_list_someone_elses_prize() {
+        let mut contract = RafflesMap::init(BENEFICIARY.parse().unwrap());
+        let prize = JsonToken { token_id: "1".to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None };
+        set_context("carol.near", 0, 0);
+        assert!(new_raffle(&mut contract, 1, 1, vec![prize.clone()]));
+
+        // the contract owns the NFT, but mallory didn't send it
+        set_context("mallory.near", 0, 0);
+        contract.add_new_raffle(U64(1), U128(1), vec![prize], None, None, None, None, None, None);
+    }
+
+    #[test]
+    fn failed_listing_frees_the_prize_for_withdrawal() {
+        let mut contract = RafflesMap::init(BENEFICIARY.parse().unwrap());
+        let prize = JsonToken { token_id: "1".to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None };
+        set_context("carol.near", 0, 0);
+        deposit_prizes(&mut contract, "carol.near", std::slice::from_ref(&prize));
+        contract.add_new_raffle(U64(1), U128(1), vec![prize.clone()], None, None, None, None, None, None);
+        let held = contract.get_held_prize("nft.near".parse().unwrap(), "1".to_string()).unwrap();
+        assert_eq!(held.commitment, Some(PrizeCommitment::Raffle { raffle_id: U128(1) }));
+
+        let args = NewRaffleArgs { end_time: 1, ticket_price: 1, prizes: vec![prize], max_royalty_bps: None, max_tickets: None, metadata: None, pricing: None, reserve: None, claim_hours: None };
+        set_promise_results(vec![PromiseResult::Failed, PromiseResult::Failed]);
+        assert!(!contract.check_token_ownership_and_finalize(1, args, "carol.near".parse().unwrap(), contract.creation_fee()));
+        let held = contract.get_held_prize("nft.near".parse().unwrap(), "1".to_string()).unwrap();
+        assert_eq!(held.depositor.as_str(), "carol.near");
+        assert!(held.commitment.is_none());
+
+        contract.withdraw_prize("nft.near".parse().unwrap(), "1".to_string());
+        assert!(contract.get_held_prize("nft.near".parse().unwrap(), "1".to_string()).is_none());
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        assert_eq!(receipts.last().unwrap().receiver_id.as_str(), "nft.near");
     }
 
     #[test]
     fn test_new_created_raffle() {
         // let mut contract = RafflesMap::default();
         let mut contract = RafflesMap::init(BENEFICIARY.parse().unwrap());
-        let mut vec: Vec<JsonToken> = Vec::new(b"m");
-        vec.push(&JsonToken {
+        let vec: Vec<JsonToken> = vec![JsonToken {
             token_id: "1111".to_string(),
             owner_id: env::predecessor_account_id(),
-        });
-        contract.add_new_raffle(1, 1, vec);
+            approval_id: None,
+        }];
+        assert!(new_raffle(&mut contract, 1, 1, vec));
 
-        assert_eq!(contract.raffles.get(&1u128).unwrap().participants.is_empty(), true);
-        assert_eq!(contract.raffles.get(&1u128).unwrap().winners.is_empty(), true);
+        assert!(contract.raffles.get(&1u128).unwrap().participants.is_empty());
+        assert!(contract.raffles.get(&1u128).unwrap().winners.is_empty());
         assert_eq!(contract.raffles.get(&1u128).unwrap().ticket_price, 1);
-        assert_eq!(contract.raffles.get(&1u128).unwrap().end_time, 1);
-        assert_eq!(contract.raffles.get(&1u128).unwrap().game_continues, true);
-        assert_eq!(contract.get_counter().clone(), 1);
+        assert_eq!(contract.raffles.get(&1u128).unwrap().end_time, MS_IN_HOUR);
+        assert!(contract.raffles.get(&1u128).unwrap().game_continues);
+        assert_eq!(contract.next_raffle_id().0, 2);
 
-        let mut vec1: Vec<JsonToken> = Vec::new(b"m");
-        vec1.push(&JsonToken {
-            token_id: "1111".to_string(),
-            owner_id: env::predecessor_account_id(),
-        });
+        assert_eq!(contract.raffles.get(&1u128).unwrap().prizes.first().unwrap().owner_id.to_string(), "bob.near");
+        assert_eq!(contract.raffles.get(&1u128).unwrap().prizes.first().unwrap().token_id.to_string(), "1111");
+    }
+
+    #[test]
+    fn zero_hours_raffle_lasts_a_day() {
+        let mut contract = RafflesMap::init(BENEFICIARY.parse().unwrap());
+        set_context("bob.near", 0, 5 * MS_IN_HOUR);
+        let prize = JsonToken { token_id: "1".to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None };
+        assert!(new_raffle(&mut contract, 0, 1, vec![prize]));
+        assert_eq!(contract.get_raffle_view(U128(1)).unwrap().end_time, U64(29 * MS_IN_HOUR));
+    }
+
+    #[test]
+    #[should_panic(expected = "Durations can't be more than 8760 hours")]
+    fn endless_raffle_is_rejected_before_the_listing() {
+        let mut contract = RafflesMap::init(BENEFICIARY.parse().unwrap());
+        set_context("bob.near", 0, 0);
+        let prize = JsonToken { token_id: "1".to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None };
+        deposit_prizes(&mut contract, "bob.near", std::slice::from_ref(&prize));
+        contract.add_new_raffle(U64(u64::MAX / MS_IN_HOUR + 1), U128(1), vec![prize], None, None, None, None, None, None);
+    }
 
-        assert_eq!(contract.raffles.get(&1u128).unwrap().prizes.get(0).unwrap().owner_id.to_string(), "bob.near");
-        assert_eq!(contract.raffles.get(&1u128).unwrap().prizes.get(0).unwrap().token_id.to_string(), "1111");
+    #[test]
+    #[should_panic(expected = "Durations can't be more than 8760 hours")]
+    fn approval_raffle_with_endless_claim_window_is_rejected() {
+        let mut contract = RafflesMap::init(BENEFICIARY.parse().unwrap());
+        set_approval_context(0);
+        let msg = r#"{"end_time": "1", "ticket_price": "10", "claim_hours": "18446744073709551615"}"#;
+        contract.nft_on_approve("7".to_string(), "carol.near".parse().unwrap(), 3, msg.to_string());
     }
 
     #[test]
     fn add_participant() {
         let mut contract = RafflesMap::init(BENEFICIARY.parse().unwrap());
-        let mut vec: Vec<JsonToken> = Vec::new(b"s");
-        vec.push(&JsonToken {
+        let vec: Vec<JsonToken> = vec![JsonToken {
             token_id: "1111".to_string(),
             owner_id: env::predecessor_account_id(),
-        });
-        contract.add_new_raffle(1, 1, vec);
-        contract.add_participant(1, &env::predecessor_account_id());
-        
-
-        assert_eq!(contract.get_raffle(1u128).unwrap().participants.is_empty(), false);
-        assert_eq!(contract.raffles.get(&1u128).unwrap().participants.as_vector().get(0).unwrap().to_string(), env::predecessor_account_id().to_string());
-
-        // let mut contract = RafflesMap::init(BENEFICIARY.parse().unwrap());
-        // contract.start_new_raffle(1, 10*NEAR, JsonToken { token_id: "1111".to_string(), owner_id: env::predecessor_account_id() });
+            approval_id: None,
+        }];
+        new_raffle(&mut contract, 1, 1, vec);
+        set_context("bob.near", with_storage(1), 0);
+        contract.add_participant(U128(1), &env::predecessor_account_id());
 
-        // set_context("donor_a", 1000*NEAR);
 
-        // contract.add_participant(1, "donor_a".parse().unwrap());
-        // assert_eq!(contract.get_raffle(1).cloned().unwrap().get_participants().get(0).unwrap().to_string(), "donor_a");
+        assert!(!contract.get_raffle(1u128).unwrap().participants.is_empty());
+        assert_eq!(contract.raffles.get(&1u128).unwrap().participants.as_vector().get(0).unwrap().to_string(), env::predecessor_account_id().to_string());
+        assert_eq!(contract.get_revenue(U128(1u128)).0, 1);
     }
 
     #[test]
     fn test_winner_and_game_continues() {
         let mut contract = RafflesMap::default();
-        let mut vec: Vec<JsonToken> = Vec::new();
-        vec.push(JsonToken {
+        let vec: Vec<JsonToken> = vec![JsonToken {
             token_id: "1111".to_string(),
             owner_id: env::predecessor_account_id(),
-        });
-        contract.add_new_raffle(1, 1, vec);
-        contract.add_participant(1, &env::predecessor_account_id());
+            approval_id: None,
+        }];
+        new_raffle(&mut contract, 1, 1, vec);
+        set_context("bob.near", with_storage(1), 0);
+        contract.add_participant(U128(1), &env::predecessor_account_id());
 
         let nft = JsonToken {
             token_id: "1111".to_string(),
             owner_id: env::predecessor_account_id(),
+            approval_id: None,
         };
 
-        assert_eq!(contract.get_raffle(1u128).unwrap().participants.is_empty(), false);
-        assert_eq!(contract.raffles.get(&1u128).unwrap().prizes.get(0).unwrap().owner_id.to_string(), "bob.near");
-        assert_eq!(contract.raffles.get(&1u128).unwrap().prizes.get(0).unwrap().token_id.to_string(), "1111");
+        assert!(!contract.get_raffle(1u128).unwrap().participants.is_empty());
+        assert_eq!(contract.raffles.get(&1u128).unwrap().prizes.first().unwrap().owner_id.to_string(), "bob.near");
+        assert_eq!(contract.raffles.get(&1u128).unwrap().prizes.first().unwrap().token_id.to_string(), "1111");
         assert_eq!(contract.raffles.get(&1u128).unwrap().participants.as_vector().get(0).unwrap().to_string(), "bob.near");
 
-        contract.cancel_raffle(1u128);
+        set_context("bob.near", 0, 2 * MS_IN_HOUR);
+        contract.cancel_raffle(U128(1u128));
 
-        assert_eq!(contract.get_raffle(1u128).unwrap().winners.is_empty(), false);
+        assert!(!contract.get_raffle(1u128).unwrap().winners.is_empty());
         assert_eq!(contract.raffles.get(&1u128).unwrap().winners.get(0).unwrap().winner_wallet_account_id.to_string(), "bob.near");
         assert_eq!(contract.raffles.get(&1u128).unwrap().winners.get(0).unwrap().prize, nft);
-        assert_eq!(contract.raffles.get(&1u128).unwrap().game_continues, false);
+        assert!(!contract.raffles.get(&1u128).unwrap().game_continues);
     }
 
     #[test]
     fn test_one_acc_one_time() {
         let mut contract = RafflesMap::default();
-        let mut vec: Vec<JsonToken> = Vec::new(b"m");
+        let vec: Vec<JsonToken> = vec![
+            JsonToken {
+                token_id: "1".to_string(),
+                owner_id: env::predecessor_account_id(),
+                approval_id: None,
+            },
+            JsonToken {
+                token_id: "2".to_string(),
+                owner_id: env::predecessor_account_id(),
+                approval_id: None,
+            },
+            JsonToken {
+                token_id: "3".to_string(),
+                owner_id: env::predecessor_account_id(),
+                approval_id: None,
+            },
+            JsonToken {
+                token_id: "4".to_string(),
+                owner_id: env::predecessor_account_id(),
+                approval_id: None,
+            },
+        ];
 
-        vec.push(&JsonToken {
-            token_id: "1".to_string(),
-            owner_id: env::predecessor_account_id(),
-        });
-        vec.push(&JsonToken {
-            token_id: "2".to_string(),
-            owner_id: env::predecessor_account_id(),
-        });
-        vec.push(&JsonToken {
-            token_id: "3".to_string(),
-            owner_id: env::predecessor_account_id(),
-        });
-        vec.push(&JsonToken {
-            token_id: "4".to_string(),
+        new_raffle(&mut contract, 1, 1, vec);
+
+        set_context("bob.near", NEAR, 0);
+        contract.add_participant(U128(1), &env::predecessor_account_id());
+        contract.add_participant(U128(1), &env::predecessor_account_id());
+        contract.add_participant(U128(1), &env::predecessor_account_id());
+        contract.add_participant(U128(1), &env::predecessor_account_id());
+
+        assert_eq!(contract.raffles.get(&1u128).unwrap().participants.len(), 1);
+        assert!(!contract.add_participant(U128(1), &env::predecessor_account_id()));
+        assert!(contract.add_participant(U128(1), &AccountId::new_unchecked("alice.near".to_string())));
+        assert_eq!(contract.raffles.get(&1u128).unwrap().participants.len(), 2);
+    }
+
+    #[test]
+    fn test_timer() {
+        let mut contract = RafflesMap::default();
+        let vec: Vec<JsonToken> = vec![JsonToken {
+            token_id: "1111".to_string(),
             owner_id: env::predecessor_account_id(),
-        });
+            approval_id: None,
+        }];
+        new_raffle(&mut contract, 1, 1, vec);
+        contract.cancel_raffle(U128(1u128));
+        assert!(contract.raffles.get(&1u128).unwrap().game_continues);
+    }
 
-        contract.add_new_raffle(1, 1, vec);
+    #[test]
+    fn test_split_payout_under_cap() {
+        let payout: HashMap<AccountId, U128> = HashMap::from([
+            ("artist.near".parse().unwrap(), U128(50)),
+            ("alice.near".parse().unwrap(), U128(950)),
+        ]);
+        let (royalties, creator_share) =
+            split_payout(1000, &payout, 1_000, &["alice.near".parse().unwrap()]);
+
+        assert_eq!(royalties, vec![("artist.near".parse().unwrap(), 50)]);
+        assert_eq!(creator_share, 950);
+    }
 
-        contract.add_participant(1, &env::predecessor_account_id());
-        contract.add_participant(1, &env::predecessor_account_id());
-        contract.add_participant(1, &env::predecessor_account_id());
-        contract.add_participant(1, &env::predecessor_account_id());
+    #[test]
+    fn test_split_payout_capped() {
+        let payout: HashMap<AccountId, U128> = HashMap::from([
+            ("artist.near".parse().unwrap(), U128(300)),
+            ("studio.near".parse().unwrap(), U128(100)),
+            ("alice.near".parse().unwrap(), U128(600)),
+        ]);
+        let (royalties, creator_share) =
+            split_payout(1000, &payout, 1_000, &["alice.near".parse().unwrap()]);
 
-        assert_eq!(contract.raffles.get(&1u128).unwrap().participants.len(), 1);
         assert_eq!(
-            contract.add_participant(1, &env::predecessor_account_id()),
-            false
+            royalties,
+            vec![("artist.near".parse().unwrap(), 75), ("studio.near".parse().unwrap(), 25)]
         );
-        assert_eq!(
-            contract.add_participant(1, &AccountId::new_unchecked("alice.near".to_string())),
-            true
+        assert_eq!(creator_share, 900);
+    }
+
+    #[test]
+    fn test_resolve_payout_without_royalties() {
+        let mut contract = RafflesMap::default();
+        let vec: Vec<JsonToken> = vec![JsonToken {
+            token_id: "1111".to_string(),
+            owner_id: env::predecessor_account_id(),
+            approval_id: None,
+        }];
+        new_raffle(&mut contract, 1, 1, vec);
+
+        let royalties = contract.resolve_payout(
+            Err(PromiseError::Failed),
+            1,
+            U128(NEAR),
+            "carol.near".parse().unwrap(),
         );
-        assert_eq!(contract.raffles.get(&1u128).unwrap().participants.len(), 2);
+        assert_eq!(royalties.0, 0);
     }
 
     #[test]
-    fn test_timer() {
+    fn test_raffle_from_approval() {
+        let mut contract = RafflesMap::default();
+        set_approval_context(0);
+        let msg = r#"{"end_time": "1", "ticket_price": "1"}"#;
+        let key = match contract.nft_on_approve("7".to_string(), "carol.near".parse().unwrap(), 3, msg.to_string()) {
+            PromiseOrValue::Value(key) => key,
+            PromiseOrValue::Promise(_) => panic!("expected the raffle id"),
+        };
+
+        let raffle = contract.get_raffle(key.parse().unwrap()).unwrap();
+        assert_eq!(raffle.get_creator().to_string(), "carol.near");
+        assert_eq!(raffle.prizes[0].owner_id.to_string(), "nft.near");
+        assert_eq!(raffle.prizes[0].approval_id, Some(3));
+        assert_eq!(raffle.max_royalty_bps, DEFAULT_MAX_ROYALTY_BPS);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the token owner can put it up as a prize")]
+    fn test_approval_for_someone_else() {
+        let mut contract = RafflesMap::default();
+        set_approval_context(0);
+        let msg = r#"{"end_time": "1", "ticket_price": "1"}"#;
+        contract.nft_on_approve("7".to_string(), "dave.near".parse().unwrap(), 3, msg.to_string());
+    }
+
+    #[test]
+    fn test_revoked_approval_aborts_raffle() {
+        let mut contract = RafflesMap::default();
+        set_approval_context(0);
+        let msg = r#"{"end_time": "1", "ticket_price": "1"}"#;
+        contract.nft_on_approve("7".to_string(), "carol.near".parse().unwrap(), 3, msg.to_string());
+
+        set_context("bob.near", with_storage(1), 0);
+        contract.add_participant(U128(1), &env::predecessor_account_id());
+        set_context("bob.near", 0, 2 * MS_IN_HOUR);
+        assert!(contract.cancel_raffle(U128(1)));
+
+        assert!(!contract.resolve_prize_transfer(Err(PromiseError::Failed), 1, 0));
+        let raffle = contract.get_raffle(1).unwrap();
+        assert!(raffle.aborted);
+        assert_eq!(raffle.revenue, 0);
+    }
+
+    #[test]
+    fn test_draw_in_steps() {
+        let mut contract = RafflesMap::default();
+        let vec: Vec<JsonToken> = (1..=4)
+            .map(|i| JsonToken {
+                token_id: i.to_string(),
+                owner_id: env::predecessor_account_id(),
+                approval_id: None,
+            })
+            .collect();
+        new_raffle(&mut contract, 1, 1, vec);
+        set_context("bob.near", with_storage(1), 0);
+        contract.add_participant(U128(1), &env::predecessor_account_id());
+
+        set_context("bob.near", 0, 2 * MS_IN_HOUR);
+        assert!(!contract.add_participant(U128(1), &AccountId::new_unchecked("alice.near".to_string())));
+        assert_eq!(contract.draw_step(U128(1), U64(3)), U64(3));
+        let progress = contract.get_settlement_progress(U128(1));
+        assert_eq!(progress.prizes_drawn, U64(3));
+        assert!(progress.game_continues);
+
+        assert_eq!(contract.draw_step(U128(1), U64(3)), U64(1));
+        let progress = contract.get_settlement_progress(U128(1));
+        assert_eq!(progress.prizes_drawn, U64(4));
+        assert!(!progress.game_continues);
+        assert_eq!(contract.draw_step(U128(1), U64(3)), U64(0));
+    }
+
+    #[test]
+    fn test_series_rolls_pot_into_next_round() {
+        let mut contract = RafflesMap::default();
+        set_context("carol.near", 10, 0);
+        let series_id = contract.create_raffle_series(U64(1), U128(100), 5_000, None);
+        let series = contract.get_raffle_series(series_id).unwrap();
+        assert_eq!(series.current_round, Some(U128(1)));
+        assert_eq!(contract.get_raffle(1).unwrap().pot, 10);
+
+        set_context("bob.near", with_storage(100), 0);
+        assert!(contract.add_participant(U128(1), &env::predecessor_account_id()));
+
+        set_context("bob.near", 0, 2 * MS_IN_HOUR);
+        assert!(contract.cancel_raffle(U128(1)));
+        assert_eq!(contract.get_pot_winner(U128(1)).unwrap().to_string(), "bob.near");
+
+        // half of the revenue funds round 2
+        let series = contract.get_raffle_series(series_id).unwrap();
+        assert_eq!(series.current_round, Some(U128(2)));
+        assert_eq!(series.rounds, U64(2));
+        assert_eq!(contract.get_raffle(2).unwrap().pot, 50);
+    }
+
+    #[test]
+    fn test_series_round_with_nft_funds_next_pot_when_settled() {
+        let mut contract = RafflesMap::default();
+        set_context("carol.near", 10, 0);
+        let series_id = contract.create_raffle_series(U64(1), U128(100), 5_000, None);
+        set_context("nft.near", 0, 0);
+        let msg = format!(r#"{{"series_id": "{}"}}"#, series_id.0);
+        contract.nft_on_transfer("carol.near".parse().unwrap(), "carol.near".parse().unwrap(), "7".to_string(), msg);
+
+        // round 1 only has the pot, round 2 gets the NFT and half of round 1's revenue
+        set_context("bob.near", with_storage(100), 0);
+        assert!(contract.buy_ticket(U128(1), None));
+        set_context("alice.near", 0, 2 * MS_IN_HOUR);
+        assert!(contract.cancel_raffle(U128(1)));
+        assert_eq!(contract.get_raffle(2).unwrap().pot, 50);
+
+        set_context("dave.near", with_storage(100), 2 * MS_IN_HOUR);
+        assert!(contract.buy_ticket(U128(2), None));
+        set_context("alice.near", 0, 4 * MS_IN_HOUR);
+        assert!(contract.cancel_raffle(U128(2)));
+        // nothing to raffle until round 2 is settled
+        assert_eq!(contract.get_raffle_series(series_id).unwrap().current_round, None);
+
+        assert!(contract.resolve_prize_transfer(Ok(()), 2, 0));
+        assert_eq!(contract.get_raffle_series(series_id).unwrap().current_round, Some(U128(3)));
+        assert_eq!(contract.get_raffle(3).unwrap().pot, 50);
+    }
+
+    #[test]
+    fn test_series_empty_round_rolls_over() {
+        let mut contract = RafflesMap::default();
+        set_context("carol.near", 10, 0);
+        let series_id = contract.create_raffle_series(U64(1), U128(100), 0, None);
+
+        set_context("bob.near", 0, 2 * MS_IN_HOUR);
+        contract.draw_step(U128(1), U64(1));
+
+        assert!(!contract.get_raffle(1).unwrap().game_continues);
+        let series = contract.get_raffle_series(series_id).unwrap();
+        assert_eq!(series.current_round, Some(U128(2)));
+        assert_eq!(contract.get_raffle(2).unwrap().pot, 10);
+    }
+
+    #[test]
+    fn test_referral_reward() {
+        let mut contract = RafflesMap::default();
+        contract.set_referral_bps(1_000);
+        let vec: Vec<JsonToken> = vec![JsonToken {
+            token_id: "1111".to_string(),
+            owner_id: env::predecessor_account_id(),
+            approval_id: None,
+        }];
+        new_raffle(&mut contract, 1, 1_000, vec);
+
+        set_context("bob.near", with_storage(1_000), 0);
+        assert!(contract.buy_ticket(U128(1), Some("alice.near".parse().unwrap())));
+        set_context("carol.near", with_storage(1_000), 0);
+        assert!(contract.buy_ticket(U128(1), Some("carol.near".parse().unwrap())));
+
+        let stats = contract.get_referral_stats(U128(1), "alice.near".parse().unwrap()).unwrap();
+        assert_eq!(stats.tickets, U64(1));
+        assert_eq!(stats.reward.0, 100);
+        // nobody can refer themselves
+        assert!(contract.get_referral_stats(U128(1), "carol.near".parse().unwrap()).is_none());
+
+        set_context("bob.near", 0, 2 * MS_IN_HOUR);
+        contract.cancel_raffle(U128(1));
+        contract.resolve_prize_transfer(Ok(()), 1, 0);
+        assert_eq!(contract.get_revenue(U128(1)).0, 1_900);
+
+        set_context("alice.near", 0, 2 * MS_IN_HOUR);
+        assert_eq!(contract.claim_referral_reward(U128(1)).0, 100);
+        assert!(contract.get_referral_stats(U128(1), "alice.near".parse().unwrap()).unwrap().claimed);
+        assert_eq!(contract.balance_of("alice.near".parse().unwrap(), Asset::Near).0, 100);
+    }
+
+    #[test]
+    fn test_failed_withdraw_is_credited_back() {
+        let mut contract = RafflesMap::default();
+        let vec: Vec<JsonToken> = vec![JsonToken {
+            token_id: "1111".to_string(),
+            owner_id: env::predecessor_account_id(),
+            approval_id: None,
+        }];
+        new_raffle(&mut contract, 1, 1, vec);
+        contract.resolve_payout(Err(PromiseError::Failed), 1, U128(NEAR), "carol.near".parse().unwrap());
+        assert_eq!(contract.balance_of("bob.near".parse().unwrap(), Asset::Near).0, NEAR);
+
+        set_context("bob.near", 0, 0);
+        contract.withdraw(Asset::Near);
+        assert_eq!(contract.balance_of("bob.near".parse().unwrap(), Asset::Near).0, 0);
+
+        assert!(!contract.resolve_withdraw(Err(PromiseError::Failed), "bob.near".parse().unwrap(), Asset::Near, U128(NEAR)));
+        assert_eq!(contract.balance_of("bob.near".parse().unwrap(), Asset::Near).0, NEAR);
+    }
+
+    #[test]
+    fn test_creation_fee_and_limits() {
+        let mut contract = RafflesMap::default();
+        apply_config_change(&mut contract, ConfigChange::CreationFee { token: Asset::Near, amount: U128(10) });
+        contract.set_max_open_raffles(U64(1));
+
+        set_context("bob.near", 15, 0);
+        contract.deposit();
+        let vec: Vec<JsonToken> = vec![JsonToken {
+            token_id: "1111".to_string(),
+            owner_id: env::predecessor_account_id(),
+            approval_id: None,
+        }];
+        assert!(new_raffle(&mut contract, 1, 1, vec));
+        assert_eq!(contract.balance_of("bob.near".parse().unwrap(), Asset::Near).0, 5);
+        assert_eq!(contract.balance_of(contract.beneficiary.clone(), Asset::Near).0, 10);
+        assert_eq!(contract.get_open_raffles("bob.near".parse().unwrap()), U64(1));
+
+        let config = contract.get_config();
+        assert_eq!(config.creation_fee, Fee { token: Asset::Near, amount: U128(10) });
+        assert_eq!(config.max_open_raffles, U64(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "already has 1 open raffles")]
+    fn test_open_raffles_limit() {
         let mut contract = RafflesMap::default();
-        let mut vec: Vec<JsonToken> = Vec::new();
-        vec.push(JsonToken {
+        contract.set_max_open_raffles(U64(1));
+        let vec: Vec<JsonToken> = vec![JsonToken {
             token_id: "1111".to_string(),
             owner_id: env::predecessor_account_id(),
+            approval_id: None,
+        }];
+        new_raffle(&mut contract, 1, 1, vec.clone());
+        new_raffle(&mut contract, 1, 1, vec);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't create raffles")]
+    fn test_blocked_creator() {
+        let mut contract = RafflesMap::default();
+        contract.block_creator("bob.near".parse().unwrap());
+        assert!(contract.is_blocked("bob.near".parse().unwrap()));
+        contract.add_new_raffle(U64(1), U128(1), vec![], None, None, None, None, None, None);
+    }
+
+    #[test]
+    fn test_failed_ownership_check_gives_fee_back() {
+        let mut contract = RafflesMap::default();
+        apply_config_change(&mut contract, ConfigChange::CreationFee { token: Asset::Near, amount: U128(10) });
+        set_context("bob.near", 10, 0);
+        contract.deposit();
+
+        let creator: AccountId = "bob.near".parse().unwrap();
+        let fee = contract.reserve_creation(&creator);
+        assert_eq!(contract.balance_of(creator.clone(), Asset::Near).0, 0);
+        let prize = JsonToken { token_id: "1111".to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None };
+        let args = NewRaffleArgs { end_time: 1, ticket_price: 1, prizes: vec![prize], max_royalty_bps: None, max_tickets: None, metadata: None, pricing: None, reserve: None, claim_hours: None };
+        set_promise_results(vec![PromiseResult::Failed, PromiseResult::Failed]);
+        let key = reserve_raffle_id(&mut contract);
+        assert!(!contract.check_token_ownership_and_finalize(key, args, creator.clone(), fee));
+        assert_eq!(contract.balance_of(creator.clone(), Asset::Near).0, 10);
+        assert_eq!(contract.get_open_raffles(creator), U64(0));
+    }
+
+    #[test]
+    fn test_edit_raffle_before_sales() {
+        let mut contract = RafflesMap::default();
+        let vec: Vec<JsonToken> = vec![JsonToken {
+            token_id: "1111".to_string(),
+            owner_id: env::predecessor_account_id(),
+            approval_id: None,
+        }];
+        new_raffle(&mut contract, 1, 1, vec);
+
+        let metadata = RaffleMetadata {
+            title: Some("Rare one".to_string()),
+            categories: vec!["art".to_string()],
+            ..Default::default()
+        };
+        contract.edit_raffle(U128(1), Some(U128(5)), Some(U64(2)), Some(U64(1)), Some(metadata.clone()), None, None);
+        let raffle = contract.get_raffle(1).unwrap();
+        assert_eq!(raffle.ticket_price, 5);
+        assert_eq!(raffle.end_time, 2 * MS_IN_HOUR);
+        assert_eq!(raffle.max_tickets, Some(1));
+        assert_eq!(raffle.metadata, metadata);
+        assert_eq!(contract.get_raffles_by_category("art".to_string(), U64(0), U64(10)).len(), 1);
+
+        set_context("bob.near", with_storage(5), 0);
+        assert!(contract.buy_ticket(U128(1), None));
+        // sold out
+        set_context("alice.near", with_storage(5), 0);
+        assert!(!contract.buy_ticket(U128(1), None));
+    }
+
+    #[test]
+    #[should_panic(expected = "Tickets were already sold")]
+    fn test_no_edits_after_first_ticket() {
+        let mut contract = RafflesMap::default();
+        let vec: Vec<JsonToken> = vec![JsonToken {
+            token_id: "1111".to_string(),
+            owner_id: env::predecessor_account_id(),
+            approval_id: None,
+        }];
+        new_raffle(&mut contract, 1, 1, vec);
+        set_context("alice.near", with_storage(1), 0);
+        contract.buy_ticket(U128(1), None);
+
+        set_context("bob.near", 0, 0);
+        contract.edit_raffle(U128(1), Some(U128(5)), None, None, None, None, None);
+    }
+
+    #[test]
+    fn test_raffles_by_category() {
+        let mut contract = RafflesMap::default();
+        let prize = |token_id: &str| JsonToken {
+            token_id: token_id.to_string(),
+            owner_id: env::current_account_id(),
+            approval_id: None,
+        };
+        let tagged = |categories: &[&str]| NewRaffleArgs {
+            end_time: 1,
+            ticket_price: 1,
+            prizes: vec![prize(categories[0])],
+            max_royalty_bps: None,
+            max_tickets: None,
+            metadata: Some(RaffleMetadata {
+                categories: categories.iter().map(|c| c.to_string()).collect(),
+                ..Default::default()
+            }),
+            pricing: None,
+            reserve: None,
+            claim_hours: None,
+        };
+        let creator: AccountId = "bob.near".parse().unwrap();
+        let key = contract.allocate_raffle_id();
+        contract.internal_create_raffle(key, creator.clone(), tagged(&["art", "pfp"]));
+        let key = contract.allocate_raffle_id();
+        contract.internal_create_raffle(key, creator.clone(), tagged(&["art"]));
+        let key = contract.allocate_raffle_id();
+        contract.internal_create_raffle(key, creator, tagged(&["music"]));
+
+        let art = contract.get_raffles_by_category("art".to_string(), U64(0), U64(10));
+        assert_eq!(art.iter().map(|raffle| raffle.id.0).collect::<Vec<u128>>(), vec![1, 2]);
+        assert_eq!(contract.get_raffles_by_category("art".to_string(), U64(1), U64(10)).len(), 1);
+        assert_eq!(contract.get_raffles_by_category("pfp".to_string(), U64(0), U64(10)).len(), 1);
+        assert!(contract.get_raffles_by_category("games".to_string(), U64(0), U64(10)).is_empty());
+        assert_eq!(contract.get_raffles(U64(0), U64(10)).len(), 3);
+        assert_eq!(contract.get_raffle_view(U128(3)).unwrap().metadata.categories, vec!["music".to_string()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "reference and reference_hash go together")]
+    fn test_reference_needs_hash() {
+        let mut contract = RafflesMap::default();
+        set_context("bob.near", 0, 0);
+        let metadata = RaffleMetadata {
+            reference: Some("https://example.com/raffle.json".to_string()),
+            ..Default::default()
+        };
+        contract.add_new_raffle(U64(1), U128(1), vec![], None, None, Some(metadata), None, None, None);
+    }
+
+    #[test]
+    fn test_prize_metadata_is_cached() {
+        let mut contract = RafflesMap::default();
+        let creator: AccountId = "bob.near".parse().unwrap();
+        let fee = contract.reserve_creation(&creator);
+        let prize = JsonToken {
+            token_id: "1111".to_string(),
+            owner_id: "nft.near".parse().unwrap(),
+            approval_id: None,
+        };
+        let token = Token {
+            token_id: prize.token_id.clone(),
+            owner_id: env::current_account_id(),
+            metadata: Some(TokenMetadata {
+                title: Some("x".repeat(200)),
+                description: None,
+                media: Some("1111.png".to_string()),
+                media_hash: None,
+                copies: None,
+                issued_at: None,
+                expires_at: None,
+                starts_at: None,
+                updated_at: None,
+                extra: None,
+                reference: None,
+                reference_hash: None,
+            }),
+            approved_account_ids: None,
+        };
+        let collection = NFTContractMetadata {
+            spec: "nft-1.0.0".to_string(),
+            name: "Mosaics".to_string(),
+            symbol: "MOSAIC".to_string(),
+            icon: None,
+            base_uri: Some("https://ipfs.io/ipfs/".to_string()),
+            reference: None,
+            reference_hash: None,
+        };
+        let args = NewRaffleArgs { end_time: 1, ticket_price: 1, prizes: vec![prize], max_royalty_bps: None, max_tickets: None, metadata: None, pricing: None, reserve: None, claim_hours: None };
+        set_promise_results(vec![json_result(&Some(token)), json_result(&collection)]);
+        let key = reserve_raffle_id(&mut contract);
+        assert!(contract.check_token_ownership_and_finalize(key, args, creator, fee));
+
+        let metadata = contract.get_raffle_view(U128(1)).unwrap().prize_metadata[0].clone().unwrap();
+        assert_eq!(metadata.title.unwrap().len(), MAX_PRIZE_TITLE_LEN);
+        assert_eq!(metadata.media, Some("https://ipfs.io/ipfs/1111.png".to_string()));
+        assert_eq!(metadata.collection_name, Some("Mosaics".to_string()));
+    }
+
+    #[test]
+    fn test_prizes_from_several_contracts() {
+        let mut contract = RafflesMap::default();
+        let creator: AccountId = "bob.near".parse().unwrap();
+        let prize = |nft_contract: &str, token_id: &str| JsonToken {
+            token_id: token_id.to_string(),
+            owner_id: nft_contract.parse().unwrap(),
+            approval_id: None,
+        };
+        let prizes = vec![prize("cats.near", "1"), prize("dogs.near", "1"), prize("cats.near", "2")];
+        let args = || NewRaffleArgs {
+            end_time: 1,
+            ticket_price: 1,
+            prizes: prizes.clone(),
+            max_royalty_bps: None,
+            max_tickets: None,
+            metadata: None,
+            pricing: None,
+            reserve: None,
+            claim_hours: None,
+        };
+        let collection = |name: &str| NFTContractMetadata {
+            spec: "nft-1.0.0".to_string(),
+            name: name.to_string(),
+            symbol: name.to_uppercase(),
+            icon: None,
+            base_uri: None,
+            reference: None,
+            reference_hash: None,
+        };
+
+        // the second cat belongs to someone else
+        let not_owned = Token { owner_id: "carol.near".parse().unwrap(), ..owned_token("2") };
+        let fee = contract.reserve_creation(&creator);
+        set_promise_results(vec![
+            json_result(&Some(owned_token("1"))),
+            json_result(&Some(owned_token("1"))),
+            json_result(&Some(not_owned)),
+            json_result(&collection("Cats")),
+            json_result(&collection("Dogs")),
+        ]);
+        let key = reserve_raffle_id(&mut contract);
+        assert!(!contract.check_token_ownership_and_finalize(key, args(), creator.clone(), fee));
+        assert!(contract.get_raffle_view(U128(1)).is_none());
+
+        let fee = contract.reserve_creation(&creator);
+        set_promise_results(vec![
+            json_result(&Some(owned_token("1"))),
+            json_result(&Some(owned_token("1"))),
+            json_result(&Some(owned_token("2"))),
+            json_result(&collection("Cats")),
+            PromiseResult::Failed,
+        ]);
+        let key = reserve_raffle_id(&mut contract);
+        assert!(contract.check_token_ownership_and_finalize(key, args(), creator, fee));
+        let collection_names: Vec<Option<String>> = contract
+            .get_raffle_view(U128(key))
+            .unwrap()
+            .prize_metadata
+            .into_iter()
+            .map(|metadata| metadata.unwrap().collection_name)
+            .collect();
+        assert_eq!(collection_names, vec![Some("Cats".to_string()), None, Some("Cats".to_string())]);
+    }
+
+    #[test]
+    #[should_panic(expected = "is listed twice")]
+    fn test_duplicate_prize() {
+        let mut contract = RafflesMap::default();
+        set_context("bob.near", 0, 0);
+        let prize = JsonToken { token_id: "1".to_string(), owner_id: "cats.near".parse().unwrap(), approval_id: None };
+        contract.add_new_raffle(U64(1), U128(1), vec![prize.clone(), prize], None, None, None, None, None, None);
+    }
+
+    #[test]
+    fn test_linear_pricing_and_refunds() {
+        let mut contract = RafflesMap::default();
+        set_approval_context(0);
+        let msg = r#"{"end_time": "1", "ticket_price": "100", "pricing": {"kind": "linear", "step": "10"}}"#;
+        contract.nft_on_approve("7".to_string(), "carol.near".parse().unwrap(), 3, msg.to_string());
+        assert_eq!(contract.quote_ticket_price(U128(1), U64(3)).0, 100 + 110 + 120);
+
+        set_context("bob.near", with_storage(100), 0);
+        assert!(contract.buy_ticket(U128(1), None));
+        // too little for the second ticket
+        set_context("dave.near", with_storage(100), 0);
+        assert!(!contract.buy_ticket(U128(1), None));
+        set_context("dave.near", with_storage(200), 0);
+        assert!(contract.buy_ticket(U128(1), None));
+        assert_eq!(contract.get_raffle_view(U128(1)).unwrap().current_price.0, 120);
+        assert_eq!(contract.get_revenue(U128(1)).0, 210);
+
+        // buyers of an aborted raffle get back what they paid
+        set_context("bob.near", 0, 2 * MS_IN_HOUR);
+        assert!(contract.cancel_raffle(U128(1)));
+        contract.resolve_prize_transfer(Err(PromiseError::Failed), 1, 0);
+        assert_eq!(contract.balance_of("bob.near".parse().unwrap(), Asset::Near).0, 100);
+        assert_eq!(contract.balance_of("dave.near".parse().unwrap(), Asset::Near).0, 110);
+    }
+
+    #[test]
+    fn test_pricing_curves() {
+        let mut contract = RafflesMap::default();
+        let quote = |contract: &mut RafflesMap, pricing: &str, now_ms: u64| {
+            set_approval_context(0);
+            let msg = format!(r#"{{"end_time": "10", "ticket_price": "1000", "pricing": {}}}"#, pricing);
+            let key: u128 = match contract.nft_on_approve("7".to_string(), "carol.near".parse().unwrap(), 3, msg) {
+                PromiseOrValue::Value(key) => key.parse().unwrap(),
+                PromiseOrValue::Promise(_) => unreachable!(),
+            };
+            set_context("bob.near", 0, now_ms);
+            contract.quote_ticket_price(U128(key), U64(3)).0
+        };
+
+        assert_eq!(quote(&mut contract, r#"{"kind": "fixed"}"#, 0), 3000);
+        assert_eq!(quote(&mut contract, r#"{"kind": "bonding_curve", "factor": "5"}"#, 0), 1000 + 1005 + 1020);
+        let early_bird = r#"{"kind": "early_bird", "discount_bps": 2000, "until": "1000"}"#;
+        assert_eq!(quote(&mut contract, early_bird, 999), 3 * 800);
+        assert_eq!(quote(&mut contract, early_bird, 1000), 3000);
+        // half way through the 10 hours
+        let dutch = r#"{"kind": "dutch_auction", "floor": "200"}"#;
+        assert_eq!(quote(&mut contract, dutch, 5 * MS_IN_HOUR), 3 * 600);
+        assert_eq!(quote(&mut contract, dutch, 10 * MS_IN_HOUR), 3 * 200);
+    }
+
+    #[test]
+    #[should_panic(expected = "is already listed")]
+    fn test_cant_add_prize_of_another_raffle() {
+        let mut contract = RafflesMap::init(BENEFICIARY.parse().unwrap());
+        let prize = |token_id: &str| JsonToken { token_id: token_id.to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None };
+        set_context("carol.near", 0, 0);
+        assert!(new_raffle(&mut contract, 1, 10, vec![prize("1")]));
+        assert!(new_raffle(&mut contract, 1, 10, vec![prize("2")]));
+
+        contract.add_raffle_prize(U128(2), prize("1"));
+    }
+
+    #[test]
+    fn test_add_and_remove_raffle_prize() {
+        let mut contract = RafflesMap::init(BENEFICIARY.parse().unwrap());
+        let prize = |token_id: &str| JsonToken { token_id: token_id.to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None };
+        let held = |contract: &RafflesMap, token_id: &str| contract.get_held_prize("nft.near".parse().unwrap(), token_id.to_string());
+        set_context("carol.near", 0, 0);
+        assert!(new_raffle(&mut contract, 1, 10, vec![prize("1")]));
+        deposit_prizes(&mut contract, "carol.near", &[prize("2")]);
+
+        // the NFT contract answers with another token
+        contract.add_raffle_prize(U128(1), prize("2"));
+        assert_eq!(held(&contract, "2").unwrap().commitment, Some(PrizeCommitment::Raffle { raffle_id: U128(1) }));
+        assert!(!contract.check_prize_and_add(Ok(owned_token("3")), Err(PromiseError::Failed), 1, prize("2")));
+        assert!(held(&contract, "2").unwrap().commitment.is_none());
+
+        contract.add_raffle_prize(U128(1), prize("2"));
+        assert!(contract.check_prize_and_add(Ok(owned_token("2")), Err(PromiseError::Failed), 1, prize("2")));
+        assert_eq!(contract.get_raffle_view(U128(1)).unwrap().prizes.len(), 2);
+
+        contract.remove_raffle_prize(U128(1), prize("2"));
+        assert!(held(&contract, "2").is_none());
+        assert_eq!(contract.get_raffle_view(U128(1)).unwrap().prizes.len(), 1);
+    }
+
+    #[test]
+    fn test_prize_check_fails_after_a_ticket_is_sold() {
+        let mut contract = RafflesMap::init(BENEFICIARY.parse().unwrap());
+        let prize = |token_id: &str| JsonToken { token_id: token_id.to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None };
+        set_context("carol.near", 0, 0);
+        assert!(new_raffle(&mut contract, 1, 10, vec![prize("1")]));
+        deposit_prizes(&mut contract, "carol.near", &[prize("2")]);
+        contract.add_raffle_prize(U128(1), prize("2"));
+
+        // bob buys a ticket while the NFT contract is being asked
+        set_context("bob.near", with_storage(10), 0);
+        assert!(contract.buy_ticket(U128(1), None));
+        assert!(!contract.check_prize_and_add(Ok(owned_token("2")), Err(PromiseError::Failed), 1, prize("2")));
+        assert_eq!(contract.get_raffle_view(U128(1)).unwrap().prizes.len(), 1);
+        assert!(contract.get_held_prize("nft.near".parse().unwrap(), "2".to_string()).unwrap().commitment.is_none());
+    }
+
+    #[test]
+    fn test_reserve_extends_once_then_aborts() {
+        let mut contract = RafflesMap::default();
+        set_approval_context(0);
+        let msg = r#"{"end_time": "1", "ticket_price": "10", "reserve": {"revenue": "25", "extension_hours": "2"}}"#;
+        contract.nft_on_approve("7".to_string(), "carol.near".parse().unwrap(), 3, msg.to_string());
+        set_context("bob.near", with_storage(10), 0);
+        assert!(contract.buy_ticket(U128(1), None));
+
+        set_context("bob.near", 0, 2 * MS_IN_HOUR);
+        assert!(!contract.cancel_raffle(U128(1)));
+        let raffle = contract.get_raffle_view(U128(1)).unwrap();
+        assert!(raffle.extended && raffle.game_continues);
+        assert_eq!(raffle.end_time, U64(4 * MS_IN_HOUR));
+
+        set_context("dave.near", with_storage(10), 3 * MS_IN_HOUR);
+        assert!(contract.buy_ticket(U128(1), None));
+        set_context("bob.near", 0, 5 * MS_IN_HOUR);
+        assert!(!contract.cancel_raffle(U128(1)));
+        let raffle = contract.get_raffle_view(U128(1)).unwrap();
+        assert!(raffle.aborted && !raffle.game_continues);
+        assert_eq!(contract.balance_of("bob.near".parse().unwrap(), Asset::Near).0, 10);
+        assert_eq!(contract.balance_of("dave.near".parse().unwrap(), Asset::Near).0, 10);
+        assert_eq!(contract.get_open_raffles("carol.near".parse().unwrap()), U64(0));
+    }
+
+    #[test]
+    fn test_aborted_raffle_returns_prize_to_depositor() {
+        let mut contract = RafflesMap::init(BENEFICIARY.parse().unwrap());
+        let prize = JsonToken { token_id: "1".to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None };
+        set_context("carol.near", 0, 0);
+        assert!(new_raffle(&mut contract, 1, 10, vec![prize]));
+
+        // nobody bought a ticket
+        set_context("bob.near", 0, 2 * MS_IN_HOUR);
+        assert!(!contract.cancel_raffle(U128(1)));
+        assert!(contract.get_held_prize("nft.near".parse().unwrap(), "1".to_string()).is_none());
+        let returned = near_sdk::test_utils::get_created_receipts().into_iter().any(|receipt| {
+            receipt.receiver_id.as_str() == "nft.near"
+                && receipt.actions.iter().any(|action| match action {
+                    near_sdk::mock::VmAction::FunctionCall { function_name, args, .. } => {
+                        function_name == "nft_transfer" && String::from_utf8_lossy(args).contains("\"receiver_id\":\"carol.near\"")
+                    }
+                    _ => false,
+                })
         });
-        contract.add_new_raffle(1, 1, vec);
-        contract.cancel_raffle(1u128);
-        assert_eq!(contract.raffles.get(&1u128).unwrap().game_continues, true);
+        assert!(returned);
+    }
+
+    #[test]
+    fn test_reserve_met() {
+        let mut contract = RafflesMap::default();
+        set_approval_context(0);
+        let msg = r#"{"end_time": "1", "ticket_price": "10", "reserve": {"revenue": "10"}}"#;
+        contract.nft_on_approve("7".to_string(), "carol.near".parse().unwrap(), 3, msg.to_string());
+        set_context("bob.near", with_storage(10), 0);
+        assert!(contract.buy_ticket(U128(1), None));
+
+        set_context("bob.near", 0, 2 * MS_IN_HOUR);
+        assert!(contract.cancel_raffle(U128(1)));
+        assert_eq!(contract.get_winners(U128(1)).len(), 1);
+    }
+
+    #[test]
+    fn test_empty_raffle_returns_prize() {
+        let mut contract = RafflesMap::default();
+        let vec: Vec<JsonToken> = vec![JsonToken {
+            token_id: "1111".to_string(),
+            owner_id: "nft.near".parse().unwrap(),
+            approval_id: None,
+        }];
+        new_raffle(&mut contract, 1, 1, vec);
+
+        set_context("alice.near", 0, 2 * MS_IN_HOUR);
+        assert!(!contract.cancel_raffle(U128(1)));
+        assert!(contract.get_raffle_view(U128(1)).unwrap().aborted);
+        assert_eq!(contract.get_open_raffles("bob.near".parse().unwrap()), U64(0));
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id.to_string(), "nft.near");
+    }
+
+    fn claim_raffle(contract: &mut RafflesMap, buyers: &[&str]) {
+        set_approval_context(0);
+        let msg = r#"{"end_time": "1", "ticket_price": "10", "claim_hours": "24"}"#;
+        contract.nft_on_approve("7".to_string(), "carol.near".parse().unwrap(), 3, msg.to_string());
+        for buyer in buyers {
+            set_context(buyer, with_storage(10), 0);
+            assert!(contract.buy_ticket(U128(1), None));
+        }
+        set_context("alice.near", 0, 2 * MS_IN_HOUR);
+        assert!(contract.cancel_raffle(U128(1)));
+    }
+
+    #[test]
+    fn test_claim_prize() {
+        let mut contract = RafflesMap::default();
+        claim_raffle(&mut contract, &["bob.near"]);
+        let winner = &contract.get_winners(U128(1))[0];
+        assert_eq!(winner.status, PrizeStatus::Claimable);
+        assert_eq!(winner.claim_deadline, Some(U64(26 * MS_IN_HOUR)));
+        // nothing was pushed to the winner
+        assert!(near_sdk::test_utils::get_created_receipts().is_empty());
+
+        set_context("bob.near", 0, 3 * MS_IN_HOUR);
+        assert_eq!(contract.claim_prize(U128(1)), U64(1));
+        assert_eq!(contract.get_winners(U128(1))[0].status, PrizeStatus::Claiming);
+        assert!(contract.resolve_prize_transfer(Ok(()), 1, 0));
+        assert_eq!(contract.get_winners(U128(1))[0].status, PrizeStatus::Delivered);
+        assert!(contract.get_raffle(1).unwrap().is_settled());
+    }
+
+    #[test]
+    fn test_unclaimed_prize_is_redrawn_then_returned() {
+        let mut contract = RafflesMap::default();
+        claim_raffle(&mut contract, &["bob.near", "dave.near"]);
+        let first = contract.get_winners(U128(1))[0].winner_wallet_account_id.clone();
+
+        set_context("alice.near", 0, 27 * MS_IN_HOUR);
+        assert_eq!(contract.expire_prize(U128(1), U64(0)), PrizeStatus::Claimable);
+        let winner = &contract.get_winners(U128(1))[0];
+        assert_ne!(winner.winner_wallet_account_id, first);
+        assert_eq!(winner.redraws, U64(1));
+        assert_eq!(winner.claim_deadline, Some(U64(51 * MS_IN_HOUR)));
+
+        // the new winner can't claim after their deadline either, the prize ends up with the creator
+        let mut now = 52 * MS_IN_HOUR;
+        while contract.get_winners(U128(1))[0].status == PrizeStatus::Claimable {
+            set_context("alice.near", 0, now);
+            contract.expire_prize(U128(1), U64(0));
+            now += 25 * MS_IN_HOUR;
+        }
+        let winner = &contract.get_winners(U128(1))[0];
+        assert_eq!(winner.status, PrizeStatus::Returned);
+        assert_eq!(winner.redraws, U64(MAX_REDRAWS));
+        assert!(contract.get_raffle(1).unwrap().is_settled());
+    }
+
+    #[test]
+    #[should_panic(expected = "Nothing to claim")]
+    fn test_claim_after_deadline() {
+        let mut contract = RafflesMap::default();
+        claim_raffle(&mut contract, &["bob.near"]);
+        set_context("bob.near", 0, 27 * MS_IN_HOUR);
+        contract.claim_prize(U128(1));
+    }
+
+    #[test]
+    fn test_unsent_prize_waits_for_a_claim() {
+        let mut contract = RafflesMap::default();
+        let vec: Vec<JsonToken> = vec![JsonToken {
+            token_id: "1111".to_string(),
+            owner_id: "nft.near".parse().unwrap(),
+            approval_id: None,
+        }];
+        new_raffle(&mut contract, 1, 10, vec);
+        set_context("dave.near", with_storage(10), 0);
+        assert!(contract.buy_ticket(U128(1), None));
+        set_context("alice.near", 0, 2 * MS_IN_HOUR);
+        assert!(contract.cancel_raffle(U128(1)));
+
+        // nothing is paid for a prize still held by the contract
+        set_context("alice.near", 0, 2 * MS_IN_HOUR);
+        assert!(!contract.resolve_prize_transfer(Err(PromiseError::Failed), 1, 0));
+        assert!(near_sdk::test_utils::get_created_receipts().is_empty());
+        let winner = &contract.get_winners(U128(1))[0];
+        assert_eq!(winner.status, PrizeStatus::Claimable);
+        assert_eq!(winner.claim_deadline, Some(U64((2 + UNSENT_CLAIM_HOURS) * MS_IN_HOUR)));
+        assert!(!contract.get_raffle(1).unwrap().is_settled());
+
+        set_context("dave.near", 0, 3 * MS_IN_HOUR);
+        assert_eq!(contract.claim_prize(U128(1)), U64(1));
+        assert!(contract.resolve_prize_transfer(Ok(()), 1, 0));
+        assert!(contract.get_raffle(1).unwrap().is_settled());
+    }
+
+    #[test]
+    fn test_ticket_storage_is_paid_by_the_buyer() {
+        let mut contract = RafflesMap::default();
+        set_approval_context(0);
+        let msg = r#"{"end_time": "1", "ticket_price": "1", "pricing": {"kind": "linear", "step": "1"}}"#;
+        contract.nft_on_approve("7".to_string(), "carol.near".parse().unwrap(), 3, msg.to_string());
+
+        set_context("bob.near", 1, 0);
+        assert!(!contract.buy_ticket(U128(1), None));
+
+        // the longest accounts, with a new referrer, still fit in what was paid
+        let buyer = "b".repeat(59) + ".near";
+        let referrer = "r".repeat(59) + ".near";
+        set_context(&buyer, with_storage(1), 0);
+        let storage_before = env::storage_usage();
+        assert!(contract.buy_ticket(U128(1), Some(referrer.parse().unwrap())));
+        assert!(env::storage_usage() - storage_before <= TICKET_STORAGE_BYTES);
+        assert_eq!(contract.get_revenue(U128(1)).0, 1);
+    }
+
+    #[test]
+    fn test_transferred_ticket_wins_for_its_holder() {
+        let mut contract = RafflesMap::default();
+        set_approval_context(0);
+        let msg = r#"{"end_time": "1", "ticket_price": "10"}"#;
+        contract.nft_on_approve("7".to_string(), "carol.near".parse().unwrap(), 3, msg.to_string());
+        set_context("bob.near", with_storage(10), 0);
+        assert!(contract.buy_ticket(U128(1), None));
+
+        let ticket = contract.nft_token("1:0".to_string()).unwrap();
+        assert_eq!(ticket.owner_id.to_string(), "bob.near");
+        assert_eq!(ticket.metadata.unwrap().title, Some("Raffle #1 ticket #0".to_string()));
+
+        set_context("bob.near", 1, 0);
+        contract.nft_transfer("dave.near".parse().unwrap(), "1:0".to_string(), None, None);
+        assert_eq!(contract.nft_supply_for_owner("dave.near".parse().unwrap()).0, 1);
+
+        set_context("alice.near", 0, 2 * MS_IN_HOUR);
+        assert!(contract.cancel_raffle(U128(1)));
+        let winner = &contract.get_winners(U128(1))[0];
+        assert_eq!(winner.winner_wallet_account_id.to_string(), "dave.near");
+        assert_eq!(winner.ticket, U64(0));
+    }
+
+    fn set_epoch(epoch_height: u64) {
+        let mut builder = VMContextBuilder::new();
+        builder.predecessor_account_id("alice.near".parse().unwrap());
+        builder.block_timestamp(2 * MS_IN_HOUR * 1_000_000);
+        builder.epoch_height(epoch_height);
+        testing_env!(builder.build());
+    }
+
+    // bob and dave hold the tickets of a no-loss raffle whose stake is unstaked at epoch 10
+    fn unstaked_no_loss_raffle(contract: &mut RafflesMap) -> u128 {
+        set_context("alice.near", 0, 0);
+        contract.add_staking_pool("pool.near".parse().unwrap());
+        set_context("carol.near", 0, 0);
+        let key = contract.create_no_loss_raffle("pool.near".parse().unwrap(), U64(1), U128(10), None, None).0;
+        for buyer in ["bob.near", "dave.near"] {
+            set_context(buyer, with_storage(10), 0);
+            assert!(contract.buy_ticket(U128(key), None));
+        }
+        assert_eq!(contract.get_revenue(U128(key)).0, 0);
+
+        // a failed deposit is staked again with the next call
+        assert_eq!(contract.stake_deposits(U128(key)).0, 20);
+        assert!(!contract.resolve_stake(Err(PromiseError::Failed), key));
+        assert_eq!(contract.stake_deposits(U128(key)).0, 20);
+        assert!(contract.resolve_stake(Ok(()), key));
+
+        set_epoch(10);
+        assert!(!contract.cancel_raffle(U128(key)));
+        assert_eq!(contract.settle_no_loss(U128(key)), NoLossStage::Unstaking);
+        assert!(contract.resolve_unstake(Ok(()), key));
+        assert_eq!(contract.record_unstaked_balance(Ok(U128(25)), key).0, 25);
+        key
+    }
+
+    #[test]
+    fn test_no_loss_raffle() {
+        let mut contract = RafflesMap::default();
+        let key = unstaked_no_loss_raffle(&mut contract);
+        assert_eq!(contract.get_no_loss(U128(key)).unwrap().unlock_epoch, Some(U64(14)));
+
+        set_epoch(14);
+        assert_eq!(contract.settle_no_loss(U128(key)), NoLossStage::Withdrawing);
+        assert_eq!(contract.resolve_stake_withdraw(Ok(()), key).0, 5);
+
+        let pot_winner = contract.get_pot_winner(U128(key)).unwrap();
+        for holder in ["bob.near", "dave.near"] {
+            let reward = if pot_winner.as_str() == holder { 5 } else { 0 };
+            assert_eq!(contract.balance_of(holder.parse().unwrap(), Asset::Near).0, 10 + reward);
+        }
+        assert_eq!(contract.get_no_loss(U128(key)).unwrap().stage, NoLossStage::Settled);
+        assert_eq!(contract.get_pool_raffle("pool.near".parse().unwrap()), None);
+        assert_eq!(contract.get_open_raffles("carol.near".parse().unwrap()), U64(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "The stake unlocks at epoch 14")]
+    fn test_no_loss_stake_is_locked() {
+        let mut contract = RafflesMap::default();
+        let key = unstaked_no_loss_raffle(&mut contract);
+        set_epoch(13);
+        contract.settle_no_loss(U128(key));
+    }
+
+    #[test]
+    fn test_jackpot() {
+        let mut contract = RafflesMap::default();
+        let run_raffle = |contract: &mut RafflesMap, token_id: &str| -> u128 {
+            set_approval_context(0);
+            let msg = r#"{"end_time": "1", "ticket_price": "100"}"#.to_string();
+            let key: u128 = match contract.nft_on_approve(token_id.to_string(), "carol.near".parse().unwrap(), 3, msg) {
+                PromiseOrValue::Value(key) => key.parse().unwrap(),
+                _ => unreachable!(),
+            };
+            for buyer in ["bob.near", "dave.near"] {
+                set_context(buyer, with_storage(100), 0);
+                assert!(contract.buy_ticket(U128(key), None));
+            }
+            set_context("alice.near", 0, 2 * MS_IN_HOUR);
+            assert!(contract.cancel_raffle(U128(key)));
+            assert!(contract.resolve_prize_transfer(Ok(()), key, 0));
+            key
+        };
+
+        contract.set_jackpot(1_000, 0);
+        let first = run_raffle(&mut contract, "1");
+        assert_eq!(contract.get_revenue(U128(first)).0, 180);
+        assert_eq!(contract.get_jackpot().amount.0, 20);
+
+        set_context("alice.near", 0, 0);
+        contract.set_jackpot(1_000, 10_000);
+        let second = run_raffle(&mut contract, "2");
+        let winner = contract.get_winners(U128(second))[0].winner_wallet_account_id.clone();
+        assert_eq!(contract.balance_of(winner.clone(), Asset::Near).0, 40);
+
+        let jackpot = contract.get_jackpot();
+        assert_eq!(jackpot.amount.0, 0);
+        assert_eq!(jackpot.wins, U64(1));
+        let history = contract.get_jackpot_history(U64(0), U64(10));
+        assert_eq!(history[0].raffle_id.0, second);
+        assert_eq!(history[0].winner, winner);
+        assert_eq!(history[0].amount.0, 40);
+        // the roll comes from the draw, not from the block the prize transfer resolved in
+        let draw = contract.get_raffle_view(U128(second)).unwrap().draw_receipt.unwrap();
+        assert_eq!(history[0].block_height, draw.block_height);
+        assert_eq!(history[0].random_seed, draw.random_seed);
+        assert_eq!(history[0].roll.0, jackpot_roll(&draw));
+    }
+
+    #[test]
+    fn test_aborted_raffle_pays_no_shares() {
+        let mut contract = RafflesMap::default();
+        apply_config_change(&mut contract, ConfigChange::PlatformFee { bps: 1_000 });
+        contract.set_jackpot(1_000, 10_000);
+        set_approval_context(0);
+        let msg = r#"{"end_time": "1", "ticket_price": "1000"}"#;
+        contract.nft_on_approve("7".to_string(), "carol.near".parse().unwrap(), 3, msg.to_string());
+        set_context("bob.near", with_storage(1_000), 0);
+        assert!(contract.buy_ticket(U128(1), None));
+
+        set_context("alice.near", 0, 2 * MS_IN_HOUR);
+        assert!(contract.cancel_raffle(U128(1)));
+        // the approval was revoked before the transfer
+        assert!(!contract.resolve_prize_transfer(Err(PromiseError::Failed), 1, 0));
+        assert_eq!(contract.balance_of("bob.near".parse().unwrap(), Asset::Near).0, 1_000);
+        assert_eq!(contract.balance_of(contract.beneficiary.clone(), Asset::Near).0, 0);
+        assert_eq!(contract.get_jackpot().amount.0, 0);
+        assert_eq!(contract.get_jackpot().wins, U64(0));
+    }
+
+    #[test]
+    fn test_usd_ticket_price() {
+        let mut contract = RafflesMap::default();
+        set_context("alice.near", 0, 0);
+        let oracle_id = Some("priceoracle.near".parse().unwrap());
+        apply_config_change(&mut contract, ConfigChange::PriceOracle { oracle_id, asset_id: None, max_age_sec: None });
+        set_approval_context(0);
+        let msg = r#"{"end_time": "1", "ticket_price": "0", "pricing": {"kind": "usd", "usd": "5000000"}}"#;
+        contract.nft_on_approve("7".to_string(), "carol.near".parse().unwrap(), 3, msg.to_string());
+
+        // $5 a NEAR, 24 decimals
+        let price_data = |timestamp_ms: u64| PriceData {
+            timestamp: U64(timestamp_ms * 1_000_000),
+            recency_duration_sec: 90,
+            prices: vec![AssetOptionalPrice {
+                asset_id: DEFAULT_NEAR_ASSET_ID.to_string(),
+                price: Some(Price { multiplier: U128(50_000), decimals: 28 }),
+            }],
+        };
+        set_context("alice.near", 0, 1_000_000);
+        let bob: AccountId = "bob.near".parse().unwrap();
+        assert!(!contract.resolve_usd_ticket(Ok(price_data(0)), 1, bob.clone(), None, U128(2 * NEAR)));
+        assert!(!contract.resolve_usd_ticket(Err(PromiseError::Failed), 1, bob.clone(), None, U128(2 * NEAR)));
+        assert!(!contract.resolve_usd_ticket(Ok(price_data(1_000_000)), 1, bob.clone(), None, U128(NEAR / 2)));
+        assert!(contract.get_participants(U128(1)).is_empty());
+
+        assert!(contract.resolve_usd_ticket(Ok(price_data(1_000_000)), 1, bob.clone(), None, U128(2 * NEAR)));
+        assert_eq!(contract.get_revenue(U128(1)).0, NEAR);
+        assert_eq!(contract.get_raffle_view(U128(1)).unwrap().current_price.0, NEAR);
+        assert_eq!(contract.get_raffle(1).unwrap().paid_by(&bob), NEAR);
+    }
+
+    #[test]
+    fn test_buy_tickets_batch() {
+        let mut contract = RafflesMap::default();
+        set_context("carol.near", 0, 0);
+        for token_id in ["1", "2"] {
+            let prize = JsonToken { token_id: token_id.to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None };
+            assert!(new_raffle(&mut contract, 1, 100, vec![prize]));
+        }
+
+        set_context("bob.near", 250 + 2 * ticket_storage_cost(), 0);
+        assert_eq!(contract.buy_tickets_batch(vec![(U128(1), U64(1)), (U128(2), U64(1))], None).0, 2 * with_storage(100));
+        for key in [1, 2] {
+            assert_eq!(contract.get_participants(U128(key)), vec!["bob.near".parse::<AccountId>().unwrap()]);
+            assert_eq!(contract.get_revenue(U128(key)).0, 100);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Raffle 2 is sold out")]
+    fn test_batch_is_all_or_nothing() {
+        let mut contract = RafflesMap::default();
+        set_context("carol.near", 0, 0);
+        for token_id in ["1", "2"] {
+            let prize = JsonToken { token_id: token_id.to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None };
+            assert!(new_raffle(&mut contract, 1, 100, vec![prize]));
+        }
+        set_context("carol.near", 0, 0);
+        contract.edit_raffle(U128(2), None, None, Some(U64(1)), None, None, None);
+        set_context("dave.near", with_storage(100), 0);
+        assert!(contract.buy_ticket(U128(2), None));
+
+        set_context("bob.near", 2 * with_storage(100), 0);
+        contract.buy_tickets_batch(vec![(U128(1), U64(1)), (U128(2), U64(1))], None);
+    }
+
+    #[test]
+    fn test_buy_ticket_for() {
+        let mut contract = RafflesMap::default();
+        set_context("carol.near", 0, 0);
+        let prize = JsonToken { token_id: "1".to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None };
+        assert!(new_raffle(&mut contract, 1, 100, vec![prize]));
+        let bob: AccountId = "bob.near".parse().unwrap();
+
+        set_context("dave.near", with_storage(150), 0);
+        assert!(contract.buy_ticket_for(U128(1), bob.clone(), Some(bob.clone())));
+        assert_eq!(contract.get_participants(U128(1)), vec![bob.clone()]);
+        assert_eq!(contract.nft_token(ticket_id(1, 0)).unwrap().owner_id, bob);
+        assert!(contract.get_referral_stats(U128(1), bob.clone()).is_none());
+
+        // the cap is per beneficiary, not per payer
+        set_context("dave.near", with_storage(100), 0);
+        assert!(!contract.buy_ticket_for(U128(1), bob, None));
+        assert!(contract.buy_ticket(U128(1), None));
+        assert_eq!(contract.get_revenue(U128(1)).0, 200);
+    }
+
+    #[test]
+    fn test_timelocked_config_change() {
+        let mut contract = RafflesMap::default();
+        let prize = |token_id: &str| JsonToken { token_id: token_id.to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None };
+        set_context("carol.near", 0, 0);
+        assert!(new_raffle(&mut contract, 100, 100, vec![prize("1")]));
+        apply_config_change(&mut contract, ConfigChange::PlatformFee { bps: 500 });
+
+        set_context("alice.near", 0, 0);
+        let id = contract.propose_config_change(ConfigChange::Beneficiary { account_id: "dao.near".parse().unwrap() });
+        assert_eq!(contract.get_config_changes()[0].executable_at, U64(CONFIG_TIMELOCK_MS));
+        set_context("alice.near", 0, CONFIG_TIMELOCK_MS);
+        contract.execute_config_change(id);
+        assert!(contract.get_config_changes().is_empty());
+        assert_eq!(contract.get_config().beneficiary.as_str(), "dao.near");
+        assert_eq!(contract.get_config().platform_fee_bps, 500);
+
+        // the raffle created before the change keeps its rate
+        set_context("carol.near", 0, CONFIG_TIMELOCK_MS);
+        assert!(new_raffle(&mut contract, 1, 100, vec![prize("2")]));
+        for key in [1, 2] {
+            set_context("bob.near", with_storage(100), CONFIG_TIMELOCK_MS);
+            assert!(contract.buy_ticket(U128(key), None));
+            set_context("alice.near", 0, CONFIG_TIMELOCK_MS + 101 * MS_IN_HOUR);
+            assert!(contract.cancel_raffle(U128(key)));
+            assert!(contract.resolve_prize_transfer(Ok(()), key, 0));
+        }
+        assert_eq!(contract.get_revenue(U128(1)).0, 100);
+        assert_eq!(contract.get_revenue(U128(2)).0, 95);
+        assert_eq!(contract.balance_of("dao.near".parse().unwrap(), Asset::Near).0, 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "Config change 0 is locked until")]
+    fn test_config_change_is_timelocked() {
+        let mut contract = RafflesMap::default();
+        set_context("alice.near", 0, 0);
+        contract.propose_config_change(ConfigChange::PlatformFee { bps: 500 });
+        set_context("alice.near", 0, CONFIG_TIMELOCK_MS - 1);
+        contract.execute_config_change(U64(0));
+    }
+
+    #[test]
+    fn test_prune_raffle() {
+        let mut contract = RafflesMap::default();
+        set_context("carol.near", 0, 0);
+        let prize = JsonToken { token_id: "1".to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None };
+        assert!(new_raffle(&mut contract, 1, 100, vec![prize]));
+        for buyer in ["bob.near", "dave.near", "erin.near"] {
+            set_context(buyer, with_storage(100), 0);
+            assert!(contract.buy_ticket(U128(1), None));
+        }
+        set_context("alice.near", 0, 2 * MS_IN_HOUR);
+        assert!(contract.cancel_raffle(U128(1)));
+        contract.resolve_prize_transfer(Ok(()), 1, 0);
+        let winner = contract.get_winners(U128(1))[0].winner_wallet_account_id.clone();
+        assert_eq!(contract.get_raffle_view(U128(1)).unwrap().settled_at, Some(U64(2 * MS_IN_HOUR)));
+
+        let mut balances: std::collections::HashMap<&str, Balance> = ["bob.near", "dave.near", "erin.near"]
+            .into_iter()
+            .map(|buyer| (buyer, contract.balance_of(buyer.parse().unwrap(), Asset::Near).0))
+            .collect();
+        set_context("alice.near", 0, 2 * MS_IN_HOUR + PRUNE_GRACE_MS);
+        assert_eq!(contract.prune_raffle(U128(1), Some(U64(2))), U64(1));
+        assert_eq!(contract.prune_raffle(U128(1), None), U64(0));
+
+        let raffle = contract.get_raffle_view(U128(1)).unwrap();
+        assert_eq!(raffle.participants, U64(0));
+        assert_eq!(raffle.winners, U64(0));
+        let summary = raffle.summary.unwrap();
+        assert_eq!(summary.participants, U64(3));
+        assert_eq!(summary.winners, vec![winner]);
+        assert!(summary.draw_receipt.is_some());
+        assert!(summary.complete);
+        assert!(contract.nft_token(ticket_id(1, 0)).is_none());
+        assert!(contract.nft_tokens_for_owner("bob.near".parse().unwrap(), None, None).is_empty());
+        // every buyer gets back the storage of their ticket, the creator isn't credited for it
+        for buyer in ["bob.near", "dave.near", "erin.near"] {
+            let credited = contract.balance_of(buyer.parse().unwrap(), Asset::Near).0 - balances.remove(buyer).unwrap();
+            assert!(credited > 0 && credited <= ticket_storage_cost());
+        }
+        assert_eq!(contract.balance_of("carol.near".parse().unwrap(), Asset::Near).0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Raffle 1 can be pruned after")]
+    fn test_prune_waits_for_the_grace_period() {
+        let mut contract = RafflesMap::default();
+        set_context("carol.near", 0, 0);
+        let prize = JsonToken { token_id: "1".to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None };
+        assert!(new_raffle(&mut contract, 1, 100, vec![prize]));
+        set_context("bob.near", with_storage(100), 0);
+        assert!(contract.buy_ticket(U128(1), None));
+        set_context("alice.near", 0, 2 * MS_IN_HOUR);
+        assert!(contract.cancel_raffle(U128(1)));
+        // the prize transfer lands an hour after the draw
+        set_context("alice.near", 0, 3 * MS_IN_HOUR);
+        contract.resolve_prize_transfer(Ok(()), 1, 0);
+        set_context("alice.near", 0, 2 * MS_IN_HOUR + PRUNE_GRACE_MS);
+        contract.prune_raffle(U128(1), None);
+    }
+
+    #[test]
+    fn test_platform_stats() {
+        let mut contract = RafflesMap::default();
+        set_context("carol.near", 0, 0);
+        for token_id in ["1", "2"] {
+            let prize = JsonToken { token_id: token_id.to_string(), owner_id: "nft.near".parse().unwrap(), approval_id: None };
+            assert!(new_raffle(&mut contract, 1, 100, vec![prize]));
+        }
+        for (buyer, key) in [("bob.near", 1), ("dave.near", 1), ("bob.near", 2)] {
+            set_context(buyer, with_storage(100), 0);
+            assert!(contract.buy_ticket(U128(key), None));
+        }
+        set_context("alice.near", 0, 2 * MS_IN_HOUR);
+        assert!(contract.cancel_raffle(U128(1)));
+        contract.resolve_prize_transfer(Ok(()), 1, 0);
+        let winner = contract.get_winners(U128(1))[0].winner_wallet_account_id.clone();
+
+        let stats = contract.get_platform_stats();
+        assert_eq!(stats.total_raffles, U64(2));
+        assert_eq!(stats.tickets_sold, U64(3));
+        assert_eq!(stats.unique_participants, U64(2));
+        assert_eq!(stats.volume, vec![(Asset::Near, U128(300))]);
+
+        let creators = contract.get_top_creators(U64(10));
+        assert_eq!(creators.len(), 1);
+        assert_eq!(creators[0].account_id.as_str(), "carol.near");
+        assert_eq!((creators[0].amount.0, creators[0].count.0), (300, 2));
+        let winners = contract.get_biggest_winners(U64(10));
+        assert_eq!(winners, vec![AccountStats { account_id: winner, amount: U128(0), count: U64(1) }]);
     }
 }
@@ -0,0 +1,181 @@
+use near_sdk::json_types::Base64VecU8;
+
+use crate::*;
+
+/*
+ * Optional description of a raffle, shaped like NEP-177 token metadata, so the frontend doesn't
+ * need its own database. Raffles are indexed by category for `get_raffles_by_category`.
+ */
+
+pub const MAX_TITLE_LEN: usize = 100;
+pub const MAX_DESCRIPTION_LEN: usize = 1_000;
+pub const MAX_URL_LEN: usize = 512;
+pub const MAX_CATEGORIES: usize = 5;
+pub const MAX_CATEGORY_LEN: usize = 32;
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RaffleMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    // URL to an image or video
+    pub media: Option<String>,
+    // URL to an off-chain JSON with more info
+    pub reference: Option<String>,
+    // sha256 of the reference JSON, required with `reference`
+    pub reference_hash: Option<Base64VecU8>,
+    #[serde(default)]
+    pub categories: Vec<String>,
+}
+
+impl RaffleMetadata {
+    pub(crate) fn assert_valid(&self) {
+        assert_max_len("title", &self.title, MAX_TITLE_LEN);
+        assert_max_len("description", &self.description, MAX_DESCRIPTION_LEN);
+        assert_max_len("media", &self.media, MAX_URL_LEN);
+        assert_max_len("reference", &self.reference, MAX_URL_LEN);
+        assert_eq!(
+            self.reference.is_some(),
+            self.reference_hash.is_some(),
+            "reference and reference_hash go together"
+        );
+        if let Some(reference_hash) = &self.reference_hash {
+            assert_eq!(reference_hash.0.len(), 32, "reference_hash has to be 32 bytes");
+        }
+
+        assert!(self.categories.len() <= MAX_CATEGORIES, "No more than {} categories", MAX_CATEGORIES);
+        for category in self.categories.iter() {
+            assert!(
+                !category.is_empty() && category.len() <= MAX_CATEGORY_LEN,
+                "category has to be 1 to {} bytes",
+                MAX_CATEGORY_LEN
+            );
+        }
+    }
+}
+
+fn assert_max_len(field: &str, value: &Option<String>, max_len: usize) {
+    if let Some(value) = value {
+        assert!(value.len() <= max_len, "{} can't be longer than {} bytes", field, max_len);
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RaffleView {
+    pub id: U128,
+    pub creator: AccountId,
+    pub end_time: U64,
+    pub ticket_price: U128,
+    pub pricing: Pricing,
+    // price of the next ticket at the time of the call
+    pub current_price: U128,
+    pub max_tickets: Option<U64>,
+    pub reserve: Option<Reserve>,
+    pub extended: bool,
+    pub claim_hours: Option<U64>,
+    pub prizes: Vec<JsonToken>,
+    pub prize_metadata: Vec<Option<PrizeMetadata>>,
+    pub participants: U64,
+    pub winners: U64,
+    pub revenue: U128,
+    pub pot: U128,
+    pub series_id: Option<U64>,
+    pub game_continues: bool,
+    pub aborted: bool,
+    pub metadata: RaffleMetadata,
+    pub no_loss: Option<NoLossView>,
+    pub draw_receipt: Option<DrawReceipt>,
+    // it can be pruned `PRUNE_GRACE_MS` later
+    pub settled_at: Option<U64>,
+    // participants and winners of a pruned raffle
+    pub summary: Option<RaffleSummary>,
+}
+
+#[near_bindgen]
+impl RafflesMap {
+    pub fn get_raffle_view(&self, key: U128) -> Option<RaffleView> {
+        let key = key.0;
+        self.raffles.get(&key).map(|raffle| raffle.to_view(key))
+    }
+
+    pub fn get_raffles(&self, from_index: U64, limit: U64) -> Vec<RaffleView> {
+        self.raffles
+            .iter()
+            .skip(from_index.0 as usize)
+            .take(limit.0 as usize)
+            .map(|(key, raffle)| raffle.to_view(key))
+            .collect()
+    }
+
+    pub fn get_raffles_by_category(&self, category: String, from_index: U64, limit: U64) -> Vec<RaffleView> {
+        let raffle_ids = match self.raffles_by_category.get(&category) {
+            Some(raffle_ids) => raffle_ids,
+            None => return vec![],
+        };
+        raffle_ids
+            .iter()
+            .skip(from_index.0 as usize)
+            .take(limit.0 as usize)
+            .map(|key| self.raffles.get(&key).unwrap().to_view(key))
+            .collect()
+    }
+}
+
+impl RafflesMap {
+    pub(crate) fn index_categories(&mut self, key: u128, metadata: &RaffleMetadata) {
+        for category in metadata.categories.iter() {
+            let mut raffle_ids = self.raffles_by_category.get(category).unwrap_or_else(|| {
+                UnorderedSet::new(StorageKey::RaffleCategory {
+                    category_hash: env::sha256(category.as_bytes()),
+                })
+            });
+            raffle_ids.insert(&key);
+            self.raffles_by_category.insert(category, &raffle_ids);
+        }
+    }
+
+    pub(crate) fn unindex_categories(&mut self, key: u128, metadata: &RaffleMetadata) {
+        for category in metadata.categories.iter() {
+            if let Some(mut raffle_ids) = self.raffles_by_category.get(category) {
+                raffle_ids.remove(&key);
+                if raffle_ids.is_empty() {
+                    self.raffles_by_category.remove(category);
+                } else {
+                    self.raffles_by_category.insert(category, &raffle_ids);
+                }
+            }
+        }
+    }
+}
+
+impl Raffle {
+    pub(crate) fn to_view(&self, key: u128) -> RaffleView {
+        RaffleView {
+            id: U128(key),
+            creator: self.creator_wallet_account_id.clone(),
+            end_time: U64(self.end_time),
+            ticket_price: U128(self.ticket_price),
+            pricing: self.pricing.clone(),
+            current_price: U128(self.current_price()),
+            max_tickets: self.max_tickets.map(U64),
+            reserve: self.reserve.clone(),
+            extended: self.extended,
+            claim_hours: self.claim_hours.map(U64),
+            prizes: self.prizes.clone(),
+            prize_metadata: self.prize_metadata.clone(),
+            participants: U64(self.participants.len()),
+            winners: U64(self.winners.len()),
+            revenue: U128(self.revenue),
+            pot: U128(self.pot),
+            series_id: self.series_id.map(U64),
+            game_continues: self.game_continues,
+            aborted: self.aborted,
+            metadata: self.metadata.clone(),
+            no_loss: self.no_loss.as_ref().map(|no_loss| no_loss.to_view()),
+            draw_receipt: self.draw_receipt.clone(),
+            settled_at: self.settled_at.map(U64),
+            summary: self.summary.clone(),
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use crate::*;
+
+/*
+ * Upgrade from the first deployed version, which only kept raffles, a counter and a greeting.
+ * Its raffles all shared one winners vector and one participants set, so they can't be carried
+ * over: every one has to be drawn before the upgrade, then they are dropped and new ids go on
+ * after the last one. The beneficiary is kept, everything else starts empty.
+ */
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct BaselineRafflesMap {
+    pub(crate) raffles: UnorderedMap<u128, BaselineRaffle>,
+    // `Counter { value }`, the id of the last raffle
+    pub(crate) counter: u128,
+    pub(crate) beneficiary: AccountId,
+    pub(crate) greeting: String,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct BaselineRaffle {
+    pub(crate) end_time: u64,
+    // token id and NFT contract
+    pub(crate) prizes: Vec<(TokenId, AccountId)>,
+    pub(crate) ticket_price: u128,
+    pub(crate) creator_wallet_account_id: AccountId,
+    pub(crate) game_continues: bool,
+    // winners are never read, only removed
+    pub(crate) winners: Vector<Vec<u8>>,
+    pub(crate) participants: UnorderedSet<AccountId>,
+}
+
+#[near_bindgen]
+impl RafflesMap {
+    #[init(ignore_state)]
+    #[private]
+    pub fn migrate() -> Self {
+        let mut old: BaselineRafflesMap = env::state_read().expect("No state to migrate");
+        let raffles: Vec<BaselineRaffle> = old.raffles.values().collect();
+        assert!(
+            raffles.iter().all(|raffle| !raffle.game_continues),
+            "Every raffle has to be drawn before the upgrade"
+        );
+
+        log!("Dropping {} raffles of the old version", raffles.len());
+        for mut raffle in raffles {
+            raffle.winners.clear();
+            raffle.participants.clear();
+        }
+        old.raffles.clear();
+        Self {
+            beneficiary: old.beneficiary,
+            next_raffle_id: old.counter + 1,
+            ..Default::default()
+        }
+    }
+}
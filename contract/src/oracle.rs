@@ -0,0 +1,145 @@
+use crate::*;
+
+/*
+ * USD ticket prices. A raffle with `Pricing::Usd` sells its tickets through `buy_usd_ticket`:
+ * the NEAR price is asked from the price oracle (NEAR's `priceoracle` interface) and the
+ * ticket is sold in the callback at that rate, overpayment going back to the buyer. Prices
+ * older than `max_age_sec` are refused and the deposit is returned. The oracle is set with a
+ * timelocked config change.
+ * `ticket_price` of such a raffle is the NEAR price of the last sold ticket, views and
+ * `quote_ticket_price` show it as an estimate.
+ */
+
+pub const DEFAULT_NEAR_ASSET_ID: &str = "wrap.near";
+pub const DEFAULT_MAX_PRICE_AGE_SEC: u64 = 90;
+// `Pricing::Usd` amounts have 6 decimals, like USDC
+pub const USD_DECIMALS: u32 = 6;
+pub const GAS_FOR_PRICE_DATA: u64 = 10 * TGAS;
+pub const GAS_FOR_USD_TICKET: u64 = 30 * TGAS;
+
+#[ext_contract(price_oracle)]
+pub trait PriceOracle {
+    fn get_price_data(&self, asset_ids: Option<Vec<String>>) -> PriceData;
+}
+
+// `multiplier / 10^decimals` USD for one smallest unit of the asset
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Price {
+    pub multiplier: U128,
+    pub decimals: u8,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetOptionalPrice {
+    pub asset_id: String,
+    pub price: Option<Price>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceData {
+    // nanoseconds
+    pub timestamp: U64,
+    pub recency_duration_sec: u32,
+    pub prices: Vec<AssetOptionalPrice>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OracleConfig {
+    pub oracle_id: Option<AccountId>,
+    // id of NEAR on the oracle
+    pub asset_id: String,
+    pub max_age_sec: U64,
+}
+
+impl Default for OracleConfig {
+    fn default() -> Self {
+        OracleConfig {
+            oracle_id: None,
+            asset_id: DEFAULT_NEAR_ASSET_ID.to_string(),
+            max_age_sec: U64(DEFAULT_MAX_PRICE_AGE_SEC),
+        }
+    }
+}
+
+#[near_bindgen]
+impl RafflesMap {
+    pub fn get_price_oracle(&self) -> OracleConfig {
+        self.oracle.clone()
+    }
+
+    // Buys a ticket of a USD raffle for the caller at the oracle's NEAR price, the deposit has to cover it and the ticket storage
+    #[payable]
+    pub fn buy_usd_ticket(&mut self, key: U128, referrer: Option<AccountId>) -> Promise {
+        let key = key.0;
+        let raffle = self.raffles.get(&key).expect("No such raffle");
+        assert!(raffle.pricing.usd().is_some(), "Raffle {} isn't priced in USD", key);
+        let oracle_id = self.oracle.oracle_id.clone().expect("No price oracle is set");
+
+        price_oracle::ext(oracle_id)
+            .with_static_gas(Gas(GAS_FOR_PRICE_DATA))
+            .get_price_data(Some(vec![self.oracle.asset_id.clone()]))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas(GAS_FOR_USD_TICKET))
+                    .resolve_usd_ticket(key, env::predecessor_account_id(), referrer, U128(env::attached_deposit())),
+            )
+    }
+
+    #[private]
+    pub fn resolve_usd_ticket(
+        &mut self,
+        #[callback_result] call_result: Result<PriceData, PromiseError>,
+        key: u128,
+        buyer: AccountId,
+        referrer: Option<AccountId>,
+        deposit: U128,
+    ) -> bool {
+        let raffle = self.raffles.get(&key).unwrap();
+        let usd = raffle.pricing.usd().unwrap();
+        let ticket_price = call_result
+            .ok()
+            .and_then(|price_data| self.fresh_near_price(price_data))
+            .and_then(|price| usd_to_yocto(usd, &price));
+        let Some(ticket_price) = ticket_price else {
+            log!("No fresh NEAR price, ticket of raffle {} isn't sold", key);
+            if deposit.0 > 0 {
+                Promise::new(buyer).transfer(deposit.0);
+            }
+            return false;
+        };
+
+        let sold = self.internal_sell_ticket(key, &buyer, &buyer, referrer, deposit.0, ticket_price);
+        if sold {
+            let mut raffle = self.raffles.get(&key).unwrap();
+            raffle.ticket_price = ticket_price;
+            self.raffles.insert(&key, &raffle);
+        }
+        sold
+    }
+}
+
+impl RafflesMap {
+    fn fresh_near_price(&self, price_data: PriceData) -> Option<Price> {
+        let age_ns = env::block_timestamp().saturating_sub(price_data.timestamp.0);
+        if age_ns > self.oracle.max_age_sec.0 * 1_000_000_000 {
+            return None;
+        }
+        price_data
+            .prices
+            .into_iter()
+            .find(|price| price.asset_id == self.oracle.asset_id)
+            .and_then(|price| price.price)
+            .filter(|price| price.multiplier.0 > 0)
+    }
+}
+
+// yoctoNEAR worth `usd` (6 decimals), None if it doesn't fit
+fn usd_to_yocto(usd: u128, price: &Price) -> Option<u128> {
+    let scale = 10u128.checked_pow(u32::from(price.decimals))?;
+    let divisor = price.multiplier.0.checked_mul(10u128.pow(USD_DECIMALS))?;
+    usd.checked_mul(scale).map(|value| value / divisor)
+}
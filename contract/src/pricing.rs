@@ -0,0 +1,109 @@
+use crate::*;
+
+/*
+ * Ticket price curves. `ticket_price` of the raffle is the base the curve starts from, the price
+ * of the next ticket only depends on the tickets sold and the block time, so buyers can check it
+ * with `quote_ticket_price` before sending their deposit.
+ * With a curve, what every participant paid is kept for refunds.
+ */
+
+// max tickets priced by one `quote_ticket_price` call
+pub const MAX_QUOTE_COUNT: u64 = 1_000;
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+#[serde(crate = "near_sdk::serde", tag = "kind", rename_all = "snake_case")]
+pub enum Pricing {
+    #[default]
+    Fixed,
+    // `discount_bps` off the ticket price until the `until` timestamp (ms)
+    EarlyBird { discount_bps: u16, until: U64 },
+    // price falls from the ticket price at creation down to `floor` at the end of the raffle
+    DutchAuction { floor: U128 },
+    // every sold ticket makes the next one `step` more expensive
+    Linear { step: U128 },
+    // ticket price + factor * sold^2
+    BondingCurve { factor: U128 },
+    // `usd` with 6 decimals, converted at the oracle price when the ticket is bought
+    Usd { usd: U128 },
+}
+
+impl Pricing {
+    pub(crate) fn assert_valid(&self, ticket_price: u128) {
+        match self {
+            Pricing::EarlyBird { discount_bps, .. } => assert!(
+                u128::from(*discount_bps) <= BPS_DENOMINATOR,
+                "discount_bps can't be more than {}",
+                BPS_DENOMINATOR
+            ),
+            Pricing::DutchAuction { floor } => {
+                assert!(floor.0 <= ticket_price, "floor can't be above the ticket price")
+            }
+            Pricing::Usd { usd } => assert!(usd.0 > 0, "USD price can't be 0"),
+            _ => {}
+        }
+    }
+
+    pub(crate) fn usd(&self) -> Option<u128> {
+        match self {
+            Pricing::Usd { usd } => Some(usd.0),
+            _ => None,
+        }
+    }
+}
+
+#[near_bindgen]
+impl RafflesMap {
+    // What the next `count` tickets cost together right now
+    pub fn quote_ticket_price(&self, key: U128, count: U64) -> U128 {
+        let (key, count) = (key.0, count.0);
+        assert!(count <= MAX_QUOTE_COUNT, "Can't quote more than {} tickets", MAX_QUOTE_COUNT);
+        let raffle = self.raffles.get(&key).expect("No such raffle");
+        let sold = raffle.participants.len();
+        let now = block_timestamp_ms();
+        U128((sold..sold + count).map(|sold| raffle.price_at(sold, now)).sum())
+    }
+}
+
+impl Raffle {
+    pub(crate) fn price_at(&self, sold: u64, now: u64) -> u128 {
+        let ticket_price = self.get_ticket_price();
+        match &self.pricing {
+            // last price paid, see `buy_usd_ticket`
+            Pricing::Fixed | Pricing::Usd { .. } => ticket_price,
+            Pricing::EarlyBird { discount_bps, until } => {
+                if now < until.0 {
+                    ticket_price - ticket_price * u128::from(*discount_bps) / BPS_DENOMINATOR
+                } else {
+                    ticket_price
+                }
+            }
+            Pricing::DutchAuction { floor } => {
+                if now >= self.end_time || self.end_time <= self.created_at {
+                    return floor.0;
+                }
+                let elapsed = u128::from(now.saturating_sub(self.created_at));
+                let duration = u128::from(self.end_time - self.created_at);
+                ticket_price - (ticket_price - floor.0) * elapsed / duration
+            }
+            Pricing::Linear { step } => step
+                .0
+                .checked_mul(u128::from(sold))
+                .and_then(|rise| rise.checked_add(ticket_price))
+                .expect("Ticket price overflow"),
+            Pricing::BondingCurve { factor } => factor
+                .0
+                .checked_mul(u128::from(sold) * u128::from(sold))
+                .and_then(|rise| rise.checked_add(ticket_price))
+                .expect("Ticket price overflow"),
+        }
+    }
+
+    pub(crate) fn current_price(&self) -> u128 {
+        self.price_at(self.participants.len(), block_timestamp_ms())
+    }
+
+    // what `participant` paid for their ticket
+    pub(crate) fn paid_by(&self, participant: &AccountId) -> u128 {
+        self.ticket_prices.get(participant).unwrap_or_else(|| self.get_ticket_price())
+    }
+}
@@ -0,0 +1,98 @@
+use near_contract_standards::non_fungible_token::metadata::NFTContractMetadata;
+use near_sdk::serde::de::DeserializeOwned;
+use near_sdk::PromiseResult;
+
+use crate::*;
+
+/*
+ * What raffle views show about a prize, copied from `nft_token` and the collection's
+ * `nft_metadata` when the prize is checked, so the frontend doesn't query every NFT contract.
+ * Texts are cut and oversized URLs dropped, a prize can't blow up the raffle's storage.
+ * Prizes may come from several NFT contracts; each one is checked on its own contract before
+ * the raffle is created.
+ */
+
+pub const MAX_PRIZE_TITLE_LEN: usize = 100;
+pub const MAX_COLLECTION_NAME_LEN: usize = 64;
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PrizeMetadata {
+    pub title: Option<String>,
+    // absolute URL, relative media is resolved against the collection's base_uri
+    pub media: Option<String>,
+    pub collection_name: Option<String>,
+}
+
+impl PrizeMetadata {
+    pub(crate) fn new(token: &Token, collection: Option<&NFTContractMetadata>) -> Self {
+        let token_metadata = token.metadata.as_ref();
+        let base_uri = collection.and_then(|collection| collection.base_uri.as_deref());
+        let media = token_metadata
+            .and_then(|metadata| metadata.media.as_deref())
+            .map(|media| resolve_media(media, base_uri))
+            .filter(|media| media.len() <= MAX_URL_LEN);
+
+        PrizeMetadata {
+            title: token_metadata
+                .and_then(|metadata| metadata.title.as_deref())
+                .map(|title| truncate(title, MAX_PRIZE_TITLE_LEN)),
+            media,
+            collection_name: collection.map(|collection| truncate(&collection.name, MAX_COLLECTION_NAME_LEN)),
+        }
+    }
+}
+
+fn resolve_media(media: &str, base_uri: Option<&str>) -> String {
+    match base_uri {
+        Some(base_uri) if !media.contains("://") && !media.starts_with("data:") => {
+            format!("{}/{}", base_uri.trim_end_matches('/'), media.trim_start_matches('/'))
+        }
+        _ => media.to_string(),
+    }
+}
+
+fn truncate(text: &str, max_len: usize) -> String {
+    let mut end = text.len().min(max_len);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text[..end].to_string()
+}
+
+// Prizes of a raffle can come from different NFT contracts, but each token only once
+pub(crate) fn assert_valid_prizes(prizes: &[JsonToken]) {
+    assert!(
+        !prizes.is_empty() && prizes.len() <= MAX_PRIZES_PER_RAFFLE,
+        "A raffle needs 1 to {} prizes",
+        MAX_PRIZES_PER_RAFFLE
+    );
+    for (index, prize) in prizes.iter().enumerate() {
+        assert_ne!(prize.owner_id, env::current_account_id(), "Tickets can't be prizes");
+        assert!(
+            !prizes[..index].contains(prize),
+            "Prize {} of {} is listed twice",
+            prize.token_id,
+            prize.owner_id
+        );
+    }
+}
+
+// NFT contracts of the prizes, in order of first appearance
+pub(crate) fn prize_collections(prizes: &[JsonToken]) -> Vec<AccountId> {
+    let mut collections: Vec<AccountId> = vec![];
+    for prize in prizes {
+        if !collections.contains(&prize.owner_id) {
+            collections.push(prize.owner_id.clone());
+        }
+    }
+    collections
+}
+
+// JSON result of a joined promise, None if the call failed or returned something else
+pub(crate) fn promise_result_json<T: DeserializeOwned>(index: u64) -> Option<T> {
+    match env::promise_result(index) {
+        PromiseResult::Successful(value) => near_sdk::serde_json::from_slice(&value).ok(),
+        _ => None,
+    }
+}
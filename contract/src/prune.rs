@@ -0,0 +1,108 @@
+use near_sdk::json_types::Base64VecU8;
+
+use crate::*;
+
+/*
+ * Pruning: `PRUNE_GRACE_MS` after a raffle is fully settled (prizes delivered or returned,
+ * refunds credited), anyone can remove its participants, per-participant prices,
+ * winners and tickets. What's left is a `RaffleSummary` for the history. Whoever paid a ticket
+ * paid its storage too, and is credited for the bytes its removal frees.
+ * Referrals stay, referrers can still claim. Big raffles are pruned over several calls.
+ */
+
+pub const PRUNE_GRACE_MS: u64 = 30 * 24 * MS_IN_HOUR;
+// gas per removed ticket: participant, price, payer, NFT token and owner index, storage credit
+pub const GAS_PER_PRUNED_TICKET: u64 = 3 * TGAS;
+
+// which randomness decided the draw, to check it against the chain
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DrawReceipt {
+    pub block_height: U64,
+    pub random_seed: Base64VecU8,
+}
+
+impl DrawReceipt {
+    pub(crate) fn now() -> Self {
+        DrawReceipt {
+            block_height: U64(env::block_height()),
+            random_seed: Base64VecU8(env::random_seed()),
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RaffleSummary {
+    pub participants: U64,
+    // prize winners in draw order
+    pub winners: Vec<AccountId>,
+    pub pot_winner: Option<AccountId>,
+    pub revenue: U128,
+    pub aborted: bool,
+    pub draw_receipt: Option<DrawReceipt>,
+    // every ticket is removed
+    pub complete: bool,
+}
+
+#[near_bindgen]
+impl RafflesMap {
+    // Removes up to `max_tickets` tickets of a settled raffle and returns how many are left
+    pub fn prune_raffle(&mut self, key: U128, max_tickets: Option<U64>) -> U64 {
+        let key = key.0;
+        let mut raffle = self.raffles.get(&key).expect("No such raffle");
+
+        if raffle.summary.is_none() {
+            let settled_at = raffle
+                .settled_at
+                .unwrap_or_else(|| env::panic_str(&format!("Raffle {} isn't settled", key)));
+            assert!(
+                block_timestamp_ms() >= settled_at + PRUNE_GRACE_MS,
+                "Raffle {} can be pruned after {}",
+                key,
+                settled_at + PRUNE_GRACE_MS
+            );
+            raffle.summary = Some(RaffleSummary {
+                participants: U64(raffle.participants.len()),
+                winners: raffle.winners.iter().map(|winner| winner.winner_wallet_account_id).collect(),
+                pot_winner: raffle.pot_winner.clone(),
+                revenue: U128(raffle.revenue),
+                aborted: raffle.aborted,
+                draw_receipt: raffle.draw_receipt.clone(),
+                complete: false,
+            });
+            raffle.winners.clear();
+        }
+        let summary = raffle.summary.as_ref().unwrap();
+        assert!(!summary.complete, "Raffle {} is already pruned", key);
+
+        // removing the last element of the set doesn't move the others
+        let mut pruned: u64 = 0;
+        while !raffle.participants.is_empty()
+            && max_tickets.is_none_or(|max| pruned < max.0)
+            && remaining_gas() >= GAS_PER_PRUNED_TICKET + GAS_RESERVE
+        {
+            let ticket = raffle.participants.len() - 1;
+            let participant = raffle.participants.as_vector().get(ticket).unwrap();
+            let storage_before = env::storage_usage();
+            raffle.participants.remove(&participant);
+            raffle.ticket_prices.remove(&participant);
+            let payer = raffle.ticket_payers.remove(&ticket);
+            self.burn_ticket(key, ticket);
+            // never more than the payer was charged, even if the sale stored less than it frees now
+            let freed = Balance::from(storage_before.saturating_sub(env::storage_usage())) * env::storage_byte_cost();
+            if let Some(payer) = payer {
+                self.credit(&payer, Asset::Near, freed.min(ticket_storage_cost()));
+            }
+            pruned += 1;
+        }
+
+        let left = raffle.participants.len();
+        if left == 0 {
+            raffle.summary.as_mut().unwrap().complete = true;
+            log!("Raffle {} is pruned", key);
+        }
+        self.raffles.insert(&key, &raffle);
+        U64(left)
+    }
+}
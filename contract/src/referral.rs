@@ -0,0 +1,112 @@
+use crate::*;
+
+/*
+ * Referrals: `buy_ticket` takes an optional referrer, and `referral_bps` of what the tickets they
+ * brought cost is kept out of the creator's revenue when the first prize is settled. Once the raffle
+ * is settled referrers move their reward to the ledger with `claim_referral_reward`.
+ */
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Referral {
+    tickets: u64,
+    // what the referred tickets cost, prices can differ between tickets
+    volume: u128,
+    claimed: bool,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReferralStats {
+    pub referrer: AccountId,
+    pub tickets: U64,
+    pub reward: U128,
+    pub claimed: bool,
+}
+
+#[near_bindgen]
+impl RafflesMap {
+    // applies to raffles created from now on
+    #[private]
+    pub fn set_referral_bps(&mut self, referral_bps: u16) {
+        assert!(
+            u128::from(referral_bps) <= BPS_DENOMINATOR,
+            "referral_bps can't be more than {}",
+            BPS_DENOMINATOR
+        );
+        self.referral_bps = referral_bps;
+    }
+
+    pub fn get_referral_bps(&self) -> u16 {
+        self.referral_bps
+    }
+
+    pub fn claim_referral_reward(&mut self, key: U128) -> U128 {
+        let key = key.0;
+        let referrer = env::predecessor_account_id();
+        let mut raffle = self.raffles.get(&key).unwrap();
+        assert!(raffle.is_settled(), "Raffle {} isn't settled yet", key);
+
+        let mut referral = raffle.referrals.get(&referrer).expect("Nothing to claim");
+        assert!(!referral.claimed, "Already claimed");
+        referral.claimed = true;
+        raffle.referrals.insert(&referrer, &referral);
+        self.raffles.insert(&key, &raffle);
+
+        let reward = raffle.referral_reward(referral.volume);
+        self.credit(&referrer, Asset::Near, reward);
+        U128(reward)
+    }
+
+    pub fn get_referral_stats(&self, key: U128, referrer: AccountId) -> Option<ReferralStats> {
+        let key = key.0;
+        let raffle = self.raffles.get(&key).unwrap();
+        raffle
+            .referrals
+            .get(&referrer)
+            .map(|referral| raffle.referral_stats(referrer, referral))
+    }
+
+    pub fn get_referrals(&self, key: U128, from_index: U64, limit: U64) -> Vec<ReferralStats> {
+        let key = key.0;
+        let raffle = self.raffles.get(&key).unwrap();
+        raffle
+            .referrals
+            .iter()
+            .skip(from_index.0 as usize)
+            .take(limit.0 as usize)
+            .map(|(referrer, referral)| raffle.referral_stats(referrer, referral))
+            .collect()
+    }
+}
+
+impl Raffle {
+    pub(crate) fn add_referral(&mut self, referrer: &AccountId, price: u128) {
+        let mut referral = self.referrals.get(referrer).unwrap_or(Referral {
+            tickets: 0,
+            volume: 0,
+            claimed: false,
+        });
+        referral.tickets += 1;
+        referral.volume += price;
+        self.referrals.insert(referrer, &referral);
+        self.referred_volume += price;
+    }
+
+    pub(crate) fn referral_reward(&self, volume: u128) -> u128 {
+        volume * u128::from(self.referral_bps) / BPS_DENOMINATOR
+    }
+
+    // rounded per referrer, the sum of the claims never goes over this
+    pub(crate) fn referral_rewards_total(&self) -> u128 {
+        self.referral_reward(self.referred_volume)
+    }
+
+    fn referral_stats(&self, referrer: AccountId, referral: Referral) -> ReferralStats {
+        ReferralStats {
+            referrer,
+            tickets: U64(referral.tickets),
+            reward: U128(self.referral_reward(referral.volume)),
+            claimed: referral.claimed,
+        }
+    }
+}
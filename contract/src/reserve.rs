@@ -0,0 +1,58 @@
+use crate::*;
+
+/*
+ * Reserve price: the least ticket money the creator accepts for the prizes. A raffle ending
+ * below it is extended once if the creator asked for it, otherwise it's aborted: tickets are
+ * refunded and the prizes go back to their depositors. Raffles without a single ticket end the
+ * same way, reserve or not.
+ */
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Reserve {
+    pub revenue: U128,
+    // hours the raffle gets once if the reserve isn't met at the end
+    pub extension_hours: Option<U64>,
+}
+
+impl Reserve {
+    pub(crate) fn extension_hours(&self) -> Option<u64> {
+        self.extension_hours.map(|hours| hours.0)
+    }
+}
+
+impl Raffle {
+    pub(crate) fn reserve_met(&self) -> bool {
+        !self.participants.is_empty()
+            && self.reserve.as_ref().is_none_or(|reserve| self.revenue >= reserve.revenue.0)
+    }
+}
+
+impl RafflesMap {
+    pub(crate) fn handle_unmet_reserve(&mut self, key: u128, mut raffle: Raffle) {
+        let extension = raffle.reserve.as_ref().and_then(|reserve| reserve.extension_hours);
+        if let (Some(U64(hours)), false) = (extension, raffle.extended) {
+            raffle.extended = true;
+            raffle.end_time = block_timestamp_ms() + hours * MS_IN_HOUR;
+            self.raffles.insert(&key, &raffle);
+            log!("Reserve of raffle {} isn't met, extended until {}", key, raffle.end_time);
+            emit_event(EventLogVariant::RaffleReserveNotMet(vec![RaffleReserveLog {
+                raffle_id: U128(key),
+                revenue: U128(raffle.revenue),
+                extended_until: Some(U64(raffle.end_time)),
+            }]));
+            return;
+        }
+
+        log!("Reserve of raffle {} isn't met, returning the prizes", key);
+        emit_event(EventLogVariant::RaffleReserveNotMet(vec![RaffleReserveLog {
+            raffle_id: U128(key),
+            revenue: U128(raffle.revenue),
+            extended_until: None,
+        }]));
+        for prize in raffle.prizes.iter() {
+            self.return_prize_to_depositor(prize);
+        }
+        self.abort_raffle(key);
+    }
+}
@@ -0,0 +1,276 @@
+use near_contract_standards::non_fungible_token::core::NonFungibleTokenReceiver;
+
+use crate::*;
+
+/*
+ * Raffle series: a template that opens a new round every time the previous one is settled.
+ * Rounds get one NFT from the series treasury (if there's any left) and the pot: NEAR carried
+ * over from unwon rounds plus `pot_share_bps` of the previous round's ticket revenue, taken when
+ * that round is settled.
+ */
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct RaffleSeries {
+    owner_id: AccountId,
+    ticket_price: u128,
+    // round length in hours, same as `end_time` of add_new_raffle
+    duration: u64,
+    // share of each round's revenue that funds the next round's pot
+    pot_share_bps: u16,
+    max_royalty_bps: u16,
+    // NFTs deposited for the next rounds
+    treasury: Vector<JsonToken>,
+    // NEAR waiting for the next round
+    rollover: u128,
+    current_round: Option<u128>,
+    rounds: u64,
+    active: bool,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RaffleSeriesView {
+    pub owner_id: AccountId,
+    pub ticket_price: U128,
+    pub duration: U64,
+    pub pot_share_bps: u16,
+    pub max_royalty_bps: u16,
+    pub treasury_size: U64,
+    pub rollover: U128,
+    pub current_round: Option<U128>,
+    pub rounds: U64,
+    pub active: bool,
+}
+
+// `msg` of `nft_transfer_call` when depositing an NFT into a series treasury
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SeriesDepositMsg {
+    series_id: U64,
+}
+
+#[near_bindgen]
+impl RafflesMap {
+    // Attached deposit is the pot of the first round
+    #[payable]
+    pub fn create_raffle_series(
+        &mut self,
+        duration: U64,
+        ticket_price: U128,
+        pot_share_bps: u16,
+        max_royalty_bps: Option<u16>,
+    ) -> U64 {
+        let duration = duration.0;
+        let ticket_price = ticket_price.0;
+        assert!(
+            u128::from(pot_share_bps) <= BPS_DENOMINATOR,
+            "pot_share_bps can't be more than {}",
+            BPS_DENOMINATOR
+        );
+        let max_royalty_bps = assert_valid_royalty_bps(max_royalty_bps);
+        assert_valid_hours(&[Some(duration)]);
+        let owner_id = env::predecessor_account_id();
+        assert!(!self.blocklist.contains(&owner_id), "{} can't create raffles", owner_id);
+        // the fee is paid once for the whole series, out of the ledger balance
+        let fee = self.creation_fee();
+        self.debit(&owner_id, fee.token.clone(), fee.amount.0);
+        self.collect_creation_fee(fee);
+
+        self.series_counter += 1;
+        let series_id = self.series_counter;
+        let series = RaffleSeries {
+            owner_id,
+            ticket_price,
+            duration,
+            pot_share_bps,
+            max_royalty_bps,
+            treasury: Vector::new(StorageKey::SeriesTreasury { series_id }),
+            rollover: env::attached_deposit(),
+            current_round: None,
+            rounds: 0,
+            active: true,
+        };
+        self.series.insert(&series_id, &series);
+
+        self.open_next_round(series_id);
+        U64(series_id)
+    }
+
+    // No new rounds after the current one; the treasury and rollover go back to the owner
+    pub fn stop_raffle_series(&mut self, series_id: U64) {
+        let series_id = series_id.0;
+        let mut series = self.series.get(&series_id).expect("No such series");
+        assert_eq!(series.owner_id, env::predecessor_account_id(), "Only the series owner can stop it");
+
+        series.active = false;
+        for prize in series.treasury.iter() {
+            self.return_prize_to_depositor(&prize);
+        }
+        series.treasury.clear();
+        self.credit(&series.owner_id, Asset::Near, series.rollover);
+        series.rollover = 0;
+        self.series.insert(&series_id, &series);
+    }
+
+    pub fn get_raffle_series(&self, series_id: U64) -> Option<RaffleSeriesView> {
+        self.series.get(&series_id.0).map(|series| RaffleSeriesView {
+            owner_id: series.owner_id,
+            ticket_price: U128(series.ticket_price),
+            duration: U64(series.duration),
+            pot_share_bps: series.pot_share_bps,
+            max_royalty_bps: series.max_royalty_bps,
+            treasury_size: U64(series.treasury.len()),
+            rollover: U128(series.rollover),
+            current_round: series.current_round.map(U128),
+            rounds: U64(series.rounds),
+            active: series.active,
+        })
+    }
+}
+
+impl RafflesMap {
+    // Opens a round if the series is running, has no open round and has something to give away
+    pub(crate) fn open_next_round(&mut self, series_id: u64) -> Option<u128> {
+        let mut series = self.series.get(&series_id).unwrap();
+        if !series.active || series.current_round.is_some() {
+            return None;
+        }
+
+        let prizes: Vec<JsonToken> = series.treasury.pop().into_iter().collect();
+        if prizes.is_empty() && series.rollover == 0 {
+            log!("Series {} has nothing to raffle, waiting for a deposit", series_id);
+            self.series.insert(&series_id, &series);
+            return None;
+        }
+
+        let key = self.allocate_raffle_id();
+        self.internal_create_raffle(
+            key,
+            series.owner_id.clone(),
+            NewRaffleArgs {
+                end_time: series.duration,
+                ticket_price: series.ticket_price,
+                prizes,
+                max_royalty_bps: Some(series.max_royalty_bps),
+                max_tickets: None,
+                metadata: None,
+                pricing: None,
+                reserve: None,
+                claim_hours: None,
+            },
+        );
+        let mut round = self.raffles.get(&key).unwrap();
+        round.series_id = Some(series_id);
+        round.pot = series.rollover;
+        self.raffles.insert(&key, &round);
+
+        series.rollover = 0;
+        series.current_round = Some(key);
+        series.rounds += 1;
+        self.series.insert(&series_id, &series);
+
+        log!("Opened round {} of series {}", key, series_id);
+        Some(key)
+    }
+
+    pub(crate) fn end_round(&mut self, series_id: u64) {
+        let mut series = self.series.get(&series_id).unwrap();
+        series.current_round = None;
+        self.series.insert(&series_id, &series);
+
+        self.open_next_round(series_id);
+    }
+
+    // Nobody played: the pot rolls over and the NFT goes back to the treasury
+    pub(crate) fn close_empty_round(&mut self, key: u128, series_id: u64) {
+        let mut round = self.raffles.get(&key).unwrap();
+        let mut series = self.series.get(&series_id).unwrap();
+
+        series.rollover += round.pot;
+        for prize in round.get_prizes().iter() {
+            series.treasury.push(prize);
+        }
+        self.series.insert(&series_id, &series);
+
+        round.pot = 0;
+        round.game_continues = false;
+        self.raffles.insert(&key, &round);
+
+        self.end_round(series_id);
+    }
+
+    // Moves the next round's share of the revenue of round `key` to the series and returns it.
+    // Rounds with an NFT are settled after the next round opened, its pot gets the share then
+    pub(crate) fn carve_next_pot(&mut self, series_id: u64, key: u128, revenue: u128) -> u128 {
+        let mut series = self.series.get(&series_id).unwrap();
+        if !series.active {
+            return 0;
+        }
+
+        let share = revenue * u128::from(series.pot_share_bps) / BPS_DENOMINATOR;
+        let next_round = series
+            .current_round
+            .filter(|round| *round != key)
+            .and_then(|round| self.raffles.get(&round).map(|raffle| (round, raffle)));
+        match next_round {
+            Some((round, mut raffle)) if !raffle.draw_started => {
+                raffle.pot += share;
+                self.raffles.insert(&round, &raffle);
+            }
+            _ => series.rollover += share,
+        }
+        self.series.insert(&series_id, &series);
+
+        self.open_next_round(series_id);
+        share
+    }
+}
+
+// NFTs sent with `nft_transfer_call` are held for a raffle of their owner, see `custody.rs`; with
+// `{"series_id": "N"}` as msg they are added to that series' treasury
+#[near_bindgen]
+impl NonFungibleTokenReceiver for RafflesMap {
+    fn nft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        previous_owner_id: AccountId,
+        token_id: TokenId,
+        msg: String,
+    ) -> PromiseOrValue<bool> {
+        if env::predecessor_account_id() == env::current_account_id() {
+            log!("Tickets can't be deposited, returning {}", token_id);
+            return PromiseOrValue::Value(true);
+        }
+        let prize = JsonToken {
+            token_id: token_id.clone(),
+            owner_id: env::predecessor_account_id(),
+            approval_id: None,
+        };
+        if msg.is_empty() {
+            self.record_deposit(&prize, previous_owner_id, None);
+            return PromiseOrValue::Value(false);
+        }
+        let series_id = match near_sdk::serde_json::from_str::<SeriesDepositMsg>(&msg) {
+            Ok(deposit) => deposit.series_id.0,
+            Err(_) => {
+                log!("Unknown msg from {}, returning token {}", sender_id, token_id);
+                return PromiseOrValue::Value(true);
+            }
+        };
+
+        let mut series = match self.series.get(&series_id) {
+            Some(series) if series.active && series.owner_id == previous_owner_id => series,
+            _ => {
+                log!("Series {} doesn't take deposits from {}", series_id, previous_owner_id);
+                return PromiseOrValue::Value(true);
+            }
+        };
+
+        series.treasury.push(&prize);
+        self.series.insert(&series_id, &series);
+        self.record_deposit(&prize, previous_owner_id, Some(PrizeCommitment::Series { series_id: U64(series_id) }));
+
+        self.open_next_round(series_id);
+        PromiseOrValue::Value(false)
+    }
+}
@@ -0,0 +1,380 @@
+use crate::*;
+
+/*
+ * No-loss raffles: ticket money isn't spent but delegated to a staking pool with
+ * `deposit_and_stake`. After the end everything is unstaked, and once the pool unlocks it
+ * (`NUM_EPOCHS_TO_UNLOCK` epochs later) it's withdrawn: the staking reward is the pot of one
+ * random ticket holder, and every ticket's principal is credited back to its holder for `withdraw`.
+ * Only pools added by the contract account can be used, and a pool backs one raffle at a time,
+ * so all our stake in it belongs to that raffle.
+ * Every step is made by `stake_deposits` / `settle_no_loss`, anyone can call them.
+ */
+
+// epochs between `unstake_all` and the unstaked balance being withdrawable
+pub const NUM_EPOCHS_TO_UNLOCK: u64 = 4;
+pub const GAS_FOR_POOL_CALL: u64 = 50 * TGAS;
+pub const GAS_FOR_POOL_VIEW: u64 = 10 * TGAS;
+
+#[ext_contract(staking_pool)]
+pub trait StakingPool {
+    fn deposit_and_stake(&mut self);
+
+    fn unstake_all(&mut self);
+
+    fn withdraw(&mut self, amount: U128);
+
+    fn get_account_unstaked_balance(&self, account_id: AccountId) -> U128;
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum NoLossStage {
+    // tickets are sold and staked
+    Staking,
+    // `unstake_all` or the unstaked balance query is in flight
+    Unstaking,
+    // waiting for the pool to unlock the unstaked balance
+    Unlocking,
+    // `withdraw` is in flight
+    Withdrawing,
+    // reward paid, principal refundable
+    Settled,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct NoLoss {
+    staking_pool: AccountId,
+    // principal of sold tickets not sent to the pool yet
+    pending: u128,
+    // principal sent with a `deposit_and_stake` that hasn't come back yet
+    in_flight: u128,
+    staked: u128,
+    // principal and reward unstaked from the pool, 0 until the pool told us
+    unstaked: u128,
+    unstaked_epoch: u64,
+    stage: NoLossStage,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NoLossView {
+    pub staking_pool: AccountId,
+    pub pending: U128,
+    pub staked: U128,
+    pub unstaked: U128,
+    pub unlock_epoch: Option<U64>,
+    pub stage: NoLossStage,
+}
+
+impl NoLoss {
+    pub(crate) fn add_principal(&mut self, amount: u128) {
+        self.pending += amount;
+    }
+
+    fn unlock_epoch(&self) -> u64 {
+        self.unstaked_epoch + NUM_EPOCHS_TO_UNLOCK
+    }
+
+    pub(crate) fn to_view(&self) -> NoLossView {
+        NoLossView {
+            staking_pool: self.staking_pool.clone(),
+            pending: U128(self.pending),
+            staked: U128(self.staked),
+            unstaked: U128(self.unstaked),
+            unlock_epoch: (self.unstaked > 0).then(|| U64(self.unlock_epoch())),
+            stage: self.stage,
+        }
+    }
+}
+
+#[near_bindgen]
+impl RafflesMap {
+    #[private]
+    pub fn add_staking_pool(&mut self, staking_pool: AccountId) {
+        self.staking_pools.insert(&staking_pool);
+    }
+
+    // Running raffles keep their pool until they are settled
+    #[private]
+    pub fn remove_staking_pool(&mut self, staking_pool: AccountId) {
+        self.staking_pools.remove(&staking_pool);
+    }
+
+    pub fn get_staking_pools(&self) -> Vec<AccountId> {
+        self.staking_pools.to_vec()
+    }
+
+    // raffle the pool's stake currently belongs to
+    pub fn get_pool_raffle(&self, staking_pool: AccountId) -> Option<U128> {
+        self.pool_raffles.get(&staking_pool).map(U128)
+    }
+
+    // The creation fee is taken like in `add_new_raffle`
+    #[payable]
+    pub fn create_no_loss_raffle(
+        &mut self,
+        staking_pool: AccountId,
+        end_time: U64,
+        ticket_price: U128,
+        max_tickets: Option<U64>,
+        metadata: Option<RaffleMetadata>,
+    ) -> U128 {
+        let end_time = end_time.0;
+        let max_tickets = max_tickets.map(|max_tickets| max_tickets.0);
+        let ticket_price = ticket_price.0;
+        assert!(self.staking_pools.contains(&staking_pool), "{} isn't a known staking pool", staking_pool);
+        assert!(
+            self.pool_raffles.get(&staking_pool).is_none(),
+            "{} already backs a raffle",
+            staking_pool
+        );
+        assert!(ticket_price > 0, "No-loss tickets can't be free");
+
+        let creator = env::predecessor_account_id();
+        self.credit(&creator, Asset::Near, env::attached_deposit());
+        let fee = self.reserve_creation(&creator);
+        let key = self.allocate_raffle_id();
+        self.internal_create_raffle(
+            key,
+            creator,
+            NewRaffleArgs {
+                end_time,
+                ticket_price,
+                prizes: vec![],
+                max_royalty_bps: None,
+                max_tickets,
+                metadata,
+                pricing: None,
+                reserve: None,
+                claim_hours: None,
+            },
+        );
+        let mut raffle = self.raffles.get(&key).unwrap();
+        // there is no revenue to share
+        raffle.referral_bps = 0;
+        raffle.jackpot_bps = 0;
+        raffle.platform_fee_bps = 0;
+        raffle.no_loss = Some(NoLoss {
+            staking_pool: staking_pool.clone(),
+            pending: 0,
+            in_flight: 0,
+            staked: 0,
+            unstaked: 0,
+            unstaked_epoch: 0,
+            stage: NoLossStage::Staking,
+        });
+        self.raffles.insert(&key, &raffle);
+        self.pool_raffles.insert(&staking_pool, &key);
+        self.collect_creation_fee(fee);
+        U128(key)
+    }
+
+    pub fn get_no_loss(&self, key: U128) -> Option<NoLossView> {
+        let key = key.0;
+        self.raffles
+            .get(&key)
+            .expect("No such raffle")
+            .no_loss
+            .map(|no_loss| no_loss.to_view())
+    }
+
+    // Sends the ticket money collected since the last call to the pool
+    pub fn stake_deposits(&mut self, key: U128) -> U128 {
+        let key = key.0;
+        let mut raffle = self.raffles.get(&key).expect("No such raffle");
+        let no_loss = raffle.no_loss.as_mut().expect("Not a no-loss raffle");
+        assert_eq!(no_loss.stage, NoLossStage::Staking, "The stake is being settled");
+        assert_eq!(no_loss.in_flight, 0, "A deposit is already on its way to the pool");
+        assert!(no_loss.pending > 0, "Nothing to stake");
+
+        let amount = no_loss.pending;
+        no_loss.pending = 0;
+        no_loss.in_flight = amount;
+        let pool = no_loss.staking_pool.clone();
+        self.raffles.insert(&key, &raffle);
+
+        staking_pool::ext(pool)
+            .with_static_gas(Gas(GAS_FOR_POOL_CALL))
+            .with_attached_deposit(amount)
+            .deposit_and_stake()
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas(10 * TGAS))
+                    .resolve_stake(key),
+            );
+        U128(amount)
+    }
+
+    // Moves an ended no-loss raffle to its next stage and returns it: unstakes everything,
+    // withdraws it once unlocked, and the withdrawal callback pays the reward
+    pub fn settle_no_loss(&mut self, key: U128) -> NoLossStage {
+        let key = key.0;
+        let mut raffle = self.raffles.get(&key).expect("No such raffle");
+        assert!(raffle.end_time < block_timestamp_ms(), "The raffle isn't over");
+        let no_loss = raffle.no_loss.as_mut().expect("Not a no-loss raffle");
+        let pool = no_loss.staking_pool.clone();
+
+        match no_loss.stage {
+            NoLossStage::Staking => {
+                assert_eq!(no_loss.in_flight, 0, "A deposit is still on its way to the pool");
+                if no_loss.staked == 0 {
+                    self.finish_no_loss(key, raffle, 0);
+                    return NoLossStage::Settled;
+                }
+                no_loss.stage = NoLossStage::Unstaking;
+                self.raffles.insert(&key, &raffle);
+                staking_pool::ext(pool)
+                    .with_static_gas(Gas(GAS_FOR_POOL_CALL))
+                    .unstake_all()
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(Gas(GAS_FOR_POOL_VIEW + 20 * TGAS))
+                            .resolve_unstake(key),
+                    );
+            }
+            // the balance query failed last time
+            NoLossStage::Unlocking if no_loss.unstaked == 0 => {
+                no_loss.stage = NoLossStage::Unstaking;
+                self.raffles.insert(&key, &raffle);
+                query_unstaked_balance(key, pool);
+            }
+            NoLossStage::Unlocking => {
+                assert!(
+                    env::epoch_height() >= no_loss.unlock_epoch(),
+                    "The stake unlocks at epoch {}",
+                    no_loss.unlock_epoch()
+                );
+                no_loss.stage = NoLossStage::Withdrawing;
+                let amount = no_loss.unstaked;
+                self.raffles.insert(&key, &raffle);
+                staking_pool::ext(pool)
+                    .with_static_gas(Gas(GAS_FOR_POOL_CALL))
+                    .withdraw(U128(amount))
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(Gas(GAS_RESERVE + MAX_REFUNDS_PER_STEP * GAS_PER_REFUND))
+                            .resolve_stake_withdraw(key),
+                    );
+            }
+            NoLossStage::Unstaking | NoLossStage::Withdrawing => env::panic_str("A pool call is in flight"),
+            NoLossStage::Settled => env::panic_str("The raffle is settled"),
+        }
+        raffle.no_loss.as_ref().unwrap().stage
+    }
+
+    // A failed deposit comes back to us, it's staked with the next call
+    #[private]
+    pub fn resolve_stake(&mut self, #[callback_result] call_result: Result<(), PromiseError>, key: u128) -> bool {
+        let mut raffle = self.raffles.get(&key).unwrap();
+        let no_loss = raffle.no_loss.as_mut().unwrap();
+        let amount = no_loss.in_flight;
+        no_loss.in_flight = 0;
+        if call_result.is_ok() {
+            no_loss.staked += amount;
+        } else {
+            log!("Couldn't stake {} for raffle {}", amount, key);
+            no_loss.pending += amount;
+        }
+        self.raffles.insert(&key, &raffle);
+        call_result.is_ok()
+    }
+
+    #[private]
+    pub fn resolve_unstake(&mut self, #[callback_result] call_result: Result<(), PromiseError>, key: u128) -> bool {
+        let mut raffle = self.raffles.get(&key).unwrap();
+        let no_loss = raffle.no_loss.as_mut().unwrap();
+        if call_result.is_err() {
+            log!("Couldn't unstake for raffle {}", key);
+            no_loss.stage = NoLossStage::Staking;
+            self.raffles.insert(&key, &raffle);
+            return false;
+        }
+        query_unstaked_balance(key, no_loss.staking_pool.clone());
+        true
+    }
+
+    #[private]
+    pub fn record_unstaked_balance(
+        &mut self,
+        #[callback_result] call_result: Result<U128, PromiseError>,
+        key: u128,
+    ) -> U128 {
+        let mut raffle = self.raffles.get(&key).unwrap();
+        let no_loss = raffle.no_loss.as_mut().unwrap();
+        // unstaked either way, a failed query is made again by `settle_no_loss`
+        no_loss.stage = NoLossStage::Unlocking;
+        match call_result {
+            Ok(balance) => {
+                no_loss.unstaked = balance.0;
+                no_loss.unstaked_epoch = env::epoch_height();
+            }
+            Err(_) => log!("Couldn't get the unstaked balance of raffle {}", key),
+        }
+        let unstaked = no_loss.unstaked;
+        self.raffles.insert(&key, &raffle);
+        U128(unstaked)
+    }
+
+    #[private]
+    pub fn resolve_stake_withdraw(&mut self, #[callback_result] call_result: Result<(), PromiseError>, key: u128) -> U128 {
+        let mut raffle = self.raffles.get(&key).unwrap();
+        let no_loss = raffle.no_loss.as_mut().unwrap();
+        if call_result.is_err() {
+            log!("Couldn't withdraw the stake of raffle {}", key);
+            no_loss.stage = NoLossStage::Unlocking;
+            self.raffles.insert(&key, &raffle);
+            return U128(0);
+        }
+        // the pool may round the principal down by a few yocto, that's taken from the reward
+        let reward = no_loss.unstaked.saturating_sub(no_loss.staked);
+        self.finish_no_loss(key, raffle, reward);
+        U128(reward)
+    }
+}
+
+impl RafflesMap {
+    // Pays the reward to a random ticket holder, frees the pool and opens the principal refunds
+    fn finish_no_loss(&mut self, key: u128, mut raffle: Raffle, reward: u128) {
+        let no_loss = raffle.no_loss.as_mut().unwrap();
+        no_loss.stage = NoLossStage::Settled;
+        self.pool_raffles.remove(&no_loss.staking_pool);
+
+        raffle.game_continues = false;
+        self.release_open_slot(raffle.get_creator());
+        raffle.draw_receipt = Some(DrawReceipt::now());
+        raffle.pot = reward;
+        let mut rng = Rng::new(&env::random_seed());
+        if let (true, Some(ticket)) = (reward > 0, raffle.get_random_ticket(&mut rng)) {
+            let pot_winner = self.ticket_holder(key, &raffle, ticket);
+            log!("Staking reward {} of raffle {} goes to {}", reward, key, pot_winner);
+            self.credit(&pot_winner, Asset::Near, reward);
+            self.record_win(&pot_winner, reward, 0);
+            raffle.pot_winner = Some(pot_winner);
+        }
+        self.raffles.insert(&key, &raffle);
+
+        self.refund_step(U128(key), U64(MAX_REFUNDS_PER_STEP));
+    }
+}
+
+impl Raffle {
+    // principal of a settled no-loss raffle is given back like the tickets of an aborted one
+    pub(crate) fn is_refundable(&self) -> bool {
+        self.aborted
+            || self
+                .no_loss
+                .as_ref()
+                .is_some_and(|no_loss| no_loss.stage == NoLossStage::Settled)
+    }
+}
+
+fn query_unstaked_balance(key: u128, pool: AccountId) {
+    staking_pool::ext(pool)
+        .with_static_gas(Gas(GAS_FOR_POOL_VIEW))
+        .get_account_unstaked_balance(env::current_account_id())
+        .then(
+            RafflesMap::ext(env::current_account_id())
+                .with_static_gas(Gas(10 * TGAS))
+                .record_unstaked_balance(key),
+        );
+}
@@ -0,0 +1,137 @@
+use crate::*;
+
+/*
+ * Platform counters for the dashboard, updated as raffles are created, tickets sold and prizes
+ * won, so nothing has to scan the raffles. Volume is what tickets sold for; refunds of aborted
+ * raffles aren't taken back. Leaderboards keep the top `LEADERBOARD_SIZE` accounts: creators by
+ * ticket volume, winners by NEAR won (pots, jackpots, staking rewards) then delivered prizes.
+ */
+
+pub const LEADERBOARD_SIZE: usize = 10;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Stats {
+    total_raffles: u64,
+    tickets_sold: u64,
+    volume: UnorderedMap<Asset, u128>,
+    participants: LookupSet<AccountId>,
+    unique_participants: u64,
+    // (volume, raffles created)
+    creators: LookupMap<AccountId, (u128, u64)>,
+    // (NEAR won, prizes delivered)
+    winners: LookupMap<AccountId, (u128, u64)>,
+    top_creators: Vec<AccountStats>,
+    top_winners: Vec<AccountStats>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            total_raffles: 0,
+            tickets_sold: 0,
+            volume: UnorderedMap::new(StorageKey::StatsVolume),
+            participants: LookupSet::new(StorageKey::StatsParticipants),
+            unique_participants: 0,
+            creators: LookupMap::new(StorageKey::StatsCreators),
+            winners: LookupMap::new(StorageKey::StatsWinners),
+            top_creators: vec![],
+            top_winners: vec![],
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountStats {
+    pub account_id: AccountId,
+    // creators: ticket volume, winners: NEAR won
+    pub amount: U128,
+    // creators: raffles created, winners: prizes delivered
+    pub count: U64,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PlatformStats {
+    pub total_raffles: U64,
+    pub tickets_sold: U64,
+    pub unique_participants: U64,
+    pub volume: Vec<(Asset, U128)>,
+}
+
+#[near_bindgen]
+impl RafflesMap {
+    pub fn get_platform_stats(&self) -> PlatformStats {
+        PlatformStats {
+            total_raffles: U64(self.stats.total_raffles),
+            tickets_sold: U64(self.stats.tickets_sold),
+            unique_participants: U64(self.stats.unique_participants),
+            volume: self.stats.volume.iter().map(|(asset, volume)| (asset, U128(volume))).collect(),
+        }
+    }
+
+    pub fn get_top_creators(&self, limit: U64) -> Vec<AccountStats> {
+        self.stats.top_creators.iter().take(limit.0 as usize).cloned().collect()
+    }
+
+    pub fn get_biggest_winners(&self, limit: U64) -> Vec<AccountStats> {
+        self.stats.top_winners.iter().take(limit.0 as usize).cloned().collect()
+    }
+
+    pub fn get_creator_stats(&self, account_id: AccountId) -> Option<AccountStats> {
+        self.stats.creators.get(&account_id).map(|(amount, count)| AccountStats {
+            account_id,
+            amount: U128(amount),
+            count: U64(count),
+        })
+    }
+
+    pub fn get_winner_stats(&self, account_id: AccountId) -> Option<AccountStats> {
+        self.stats.winners.get(&account_id).map(|(amount, count)| AccountStats {
+            account_id,
+            amount: U128(amount),
+            count: U64(count),
+        })
+    }
+}
+
+impl RafflesMap {
+    pub(crate) fn record_raffle_created(&mut self, creator: &AccountId) {
+        self.stats.total_raffles += 1;
+        let (volume, raffles) = self.stats.creators.get(creator).unwrap_or((0, 0));
+        self.stats.creators.insert(creator, &(volume, raffles + 1));
+        rank(&mut self.stats.top_creators, creator, volume, raffles + 1);
+    }
+
+    pub(crate) fn record_ticket_sold(&mut self, creator: &AccountId, participant: &AccountId, price: u128) {
+        self.stats.tickets_sold += 1;
+        let volume = self.stats.volume.get(&Asset::Near).unwrap_or(0);
+        self.stats.volume.insert(&Asset::Near, &(volume + price));
+        if self.stats.participants.insert(participant) {
+            self.stats.unique_participants += 1;
+        }
+        let (volume, raffles) = self.stats.creators.get(creator).unwrap_or((0, 0));
+        self.stats.creators.insert(creator, &(volume + price, raffles));
+        rank(&mut self.stats.top_creators, creator, volume + price, raffles);
+    }
+
+    // NEAR won, `prizes` delivered NFTs
+    pub(crate) fn record_win(&mut self, winner: &AccountId, amount: u128, prizes: u64) {
+        let (won, delivered) = self.stats.winners.get(winner).unwrap_or((0, 0));
+        let (won, delivered) = (won + amount, delivered + prizes);
+        self.stats.winners.insert(winner, &(won, delivered));
+        rank(&mut self.stats.top_winners, winner, won, delivered);
+    }
+}
+
+// updates `account` on the board and keeps the best `LEADERBOARD_SIZE`
+fn rank(board: &mut Vec<AccountStats>, account: &AccountId, amount: u128, count: u64) {
+    board.retain(|entry| &entry.account_id != account);
+    board.push(AccountStats {
+        account_id: account.clone(),
+        amount: U128(amount),
+        count: U64(count),
+    });
+    board.sort_by_key(|entry| std::cmp::Reverse((entry.amount.0, entry.count.0)));
+    board.truncate(LEADERBOARD_SIZE);
+}
@@ -0,0 +1,124 @@
+use near_contract_standards::non_fungible_token::metadata::{
+    NFTContractMetadata, NonFungibleTokenMetadataProvider, TokenMetadata, NFT_METADATA_SPEC,
+};
+use near_contract_standards::non_fungible_token::NonFungibleToken;
+use near_contract_standards::{
+    impl_non_fungible_token_approval, impl_non_fungible_token_core, impl_non_fungible_token_enumeration,
+};
+
+use crate::*;
+
+/*
+ * Tickets are NEP-171 tokens of this contract, `<raffle id>:<ticket number>`, minted on
+ * purchase. The ticket number is the buyer's index in the raffle's participants, so the draw
+ * picks a ticket and whoever holds it then wins. Refunds of aborted raffles also go to holders.
+ * Buying is still one ticket per account, holding several bought by others is fine.
+ * On top of the ticket price the buyer pays `get_ticket_storage_cost` for what the sale stores.
+ */
+
+pub const TICKETS_NAME: &str = "Raffland tickets";
+pub const TICKETS_SYMBOL: &str = "TICKET";
+// upper bound of what a sale stores: participant, price, payer, referral, ticket token with its
+// metadata and owner index, stats of a new participant (~1900 bytes with 64 char accounts)
+pub const TICKET_STORAGE_BYTES: u64 = 2_000;
+
+pub(crate) fn new_tickets() -> NonFungibleToken {
+    NonFungibleToken::new(
+        StorageKey::TicketOwners,
+        env::current_account_id(),
+        Some(StorageKey::TicketMetadata),
+        Some(StorageKey::TicketsPerOwner),
+        Some(StorageKey::TicketApprovals),
+    )
+}
+
+pub(crate) fn ticket_id(key: u128, ticket: u64) -> TokenId {
+    format!("{}:{}", key, ticket)
+}
+
+pub(crate) fn ticket_storage_cost() -> Balance {
+    Balance::from(TICKET_STORAGE_BYTES) * env::storage_byte_cost()
+}
+
+impl_non_fungible_token_core!(RafflesMap, tickets);
+impl_non_fungible_token_approval!(RafflesMap, tickets);
+impl_non_fungible_token_enumeration!(RafflesMap, tickets);
+
+#[near_bindgen]
+impl RafflesMap {
+    // Attached to every ticket purchase on top of the ticket price
+    pub fn get_ticket_storage_cost(&self) -> U128 {
+        U128(ticket_storage_cost())
+    }
+}
+
+#[near_bindgen]
+impl NonFungibleTokenMetadataProvider for RafflesMap {
+    fn nft_metadata(&self) -> NFTContractMetadata {
+        NFTContractMetadata {
+            spec: NFT_METADATA_SPEC.to_string(),
+            name: TICKETS_NAME.to_string(),
+            symbol: TICKETS_SYMBOL.to_string(),
+            icon: None,
+            base_uri: None,
+            reference: None,
+            reference_hash: None,
+        }
+    }
+}
+
+impl RafflesMap {
+    // the buyer paid `ticket_storage_cost` for the token, nothing is charged here
+    pub(crate) fn mint_ticket(&mut self, key: u128, ticket: u64, buyer: &AccountId) {
+        let metadata = TokenMetadata {
+            title: Some(format!("Raffle #{} ticket #{}", key, ticket)),
+            description: None,
+            media: None,
+            media_hash: None,
+            copies: None,
+            issued_at: Some(block_timestamp_ms().to_string()),
+            expires_at: None,
+            starts_at: None,
+            updated_at: None,
+            extra: Some(near_sdk::serde_json::json!({ "raffle_id": key.to_string(), "ticket": ticket }).to_string()),
+            reference: None,
+            reference_hash: None,
+        };
+        self.tickets
+            .internal_mint_with_refund(ticket_id(key, ticket), buyer.clone(), Some(metadata), None);
+    }
+
+    // whoever holds the ticket now
+    pub(crate) fn ticket_holder(&self, key: u128, raffle: &Raffle, ticket: u64) -> AccountId {
+        self.tickets
+            .owner_by_id
+            .get(&ticket_id(key, ticket))
+            .unwrap_or_else(|| raffle.participants.as_vector().get(ticket).unwrap())
+    }
+    // removes a ticket of a pruned raffle with everything the NFT standard keeps for it
+    pub(crate) fn burn_ticket(&mut self, key: u128, ticket: u64) {
+        let token_id = ticket_id(key, ticket);
+        let Some(holder) = self.tickets.owner_by_id.remove(&token_id) else {
+            return;
+        };
+        if let Some(metadata) = self.tickets.token_metadata_by_id.as_mut() {
+            metadata.remove(&token_id);
+        }
+        if let Some(tokens_per_owner) = self.tickets.tokens_per_owner.as_mut() {
+            if let Some(mut tokens) = tokens_per_owner.get(&holder) {
+                tokens.remove(&token_id);
+                if tokens.is_empty() {
+                    tokens_per_owner.remove(&holder);
+                } else {
+                    tokens_per_owner.insert(&holder, &tokens);
+                }
+            }
+        }
+        if let Some(approvals) = self.tickets.approvals_by_id.as_mut() {
+            approvals.remove(&token_id);
+        }
+        if let Some(next_approval_ids) = self.tickets.next_approval_id_by_id.as_mut() {
+            next_approval_ids.remove(&token_id);
+        }
+    }
+}
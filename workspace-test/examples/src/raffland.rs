@@ -33,8 +33,8 @@ async fn main() -> anyhow::Result<()> {
     let outcome = contract
         .call("add_new_raffle")
         .args_json(json!({
-            "end_time": 42,
-            "ticket_price": 42,
+            "end_time": "42",
+            "ticket_price": "42",
             "prizes": [
                 {
                     "token_id": "1111",
@@ -60,27 +60,13 @@ async fn main() -> anyhow::Result<()> {
     //     .transact()
     //     .await?;
 
-    // contract.get_greeting()
-    let result: u128 = contract
-        .call("get_counter1")
+    let result: serde_json::Value = contract
+        .call("next_raffle_id")
         .view()
         .await?
         .json()?;
 
-    println!("status: {:?}", result);
-
-    contract
-        .call("increment_counter")
-        .transact()
-        .await?;
-
-    let result: u128 = contract
-        .call("get_counter1")
-        .view()
-        .await?
-        .json()?;
-
-    println!("status: {:?}", result);
+    println!("next raffle id: {:?}", result);
 
 //  
 //   @@@@@@@ @@@@@@@@  @@@@@@ @@@@@@@  @@@@@@       @@@@@@  @@@@@@@ @@@@@@@@ @@@  @@@  @@@@@@  @@@@@@@  @@@  @@@@@@   @@@@@@     
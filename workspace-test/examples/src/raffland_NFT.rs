@@ -100,8 +100,8 @@ async fn main() -> anyhow::Result<()> {
         .await?;
 
     let request_payload1  = json!({
-            "end_time": 42,
-            "ticket_price": 42,
+            "end_time": "42",
+            "ticket_price": "42",
             "prizes": [
                 {
                     "token_id": "1",
@@ -154,13 +154,13 @@ async fn main() -> anyhow::Result<()> {
 
     println!("nft: {:?}", owner_id);
 
-    let result: u128 = owner
-        .call(&worker, &raffland_contract.id(), "get_counter1")
+    let result: serde_json::Value = owner
+        .call(&worker, &raffland_contract.id(), "next_raffle_id")
         .view()
         .await?
         .json()?;
 
-    println!("status: {:?}", result);
+    println!("next raffle id: {:?}", result);
     
 
     // market_contract